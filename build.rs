@@ -1,10 +1,14 @@
+use std::collections::HashMap;
 use std::env;
+use std::fmt::Write as _;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use proc_macro2::TokenStream;
 use quote::quote;
 
+const DAC_CODES: u16 = 4096;
+
 #[derive(Debug)]
 struct PowerLevel {
     hdr: bool,
@@ -13,62 +17,476 @@ struct PowerLevel {
 
 impl PowerLevel {
     // output of this level, 0..1
-    fn output(&self) -> f32 {
-        // taken from boost.nbt, this is R_SENSE_HDR / R_SENSE_MAIN
-        let hdr_factor = 412.0;
-        let (scale, offset) = if self.hdr {
-            // we offset the high end configs by one dac step so that we don't
-            // pick high end configs for an output level of zero
-            (1.0, 0.0)
+    fn output(&self, config: &CurveConfig) -> f32 {
+        if let Some(measured) = &config.measured {
+            return measured.output(self);
+        }
+
+        let scale = if self.hdr {
+            1.0
+        } else {
+            1.0 / config.hdr_factor
+        };
+
+        scale * (self.dac as f32 / DAC_CODES as f32)
+    }
+
+    // electrical power going into the LEDs, in watts. the LED current follows
+    // the DAC, with the forward voltage rising roughly linearly with it
+    fn power(&self, config: &CurveConfig) -> f32 {
+        let scale = if self.hdr {
+            1.0
+        } else {
+            1.0 / config.hdr_factor
+        };
+        let current = scale * (self.dac as f32 / DAC_CODES as f32);
+
+        let volts = config.led_volts_min + (config.led_volts_max - config.led_volts_min) * current;
+
+        current * config.max_current * volts
+    }
+}
+
+#[derive(Debug)]
+enum Curve {
+    Power { exponent: f32 },
+    Exponential { min_output: f32 },
+    Table { points: Vec<f32> },
+}
+
+impl Curve {
+    // target output for a position through the ramp, 0..1
+    fn output(&self, l: f32) -> f32 {
+        match self {
+            Curve::Power { exponent } => l.powf(*exponent),
+            Curve::Exponential { min_output } => min_output.powf(1.0 - l),
+            Curve::Table { points } => {
+                let pos = l * (points.len() - 1) as f32;
+                let idx = (pos.floor() as usize).min(points.len() - 2);
+                let t = pos - idx as f32;
+
+                // brightness is perceived logarithmically, so interpolate there
+                let (lo, hi) = (points[idx].ln(), points[idx + 1].ln());
+                (lo + (hi - lo) * t).exp()
+            }
+        }
+    }
+}
+
+// lumens measured at a handful of DAC codes in each gear, the output in
+// between is interpolated linearly
+#[derive(Debug)]
+struct Measured {
+    path: PathBuf,
+    low: Vec<(u16, f32)>,
+    high: Vec<(u16, f32)>,
+    max_lumens: f32,
+}
+
+impl Measured {
+    // `hdr, dac, lumens` lines, with # comments and an optional header
+    fn load(path: &Path) -> Self {
+        let src = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("couldn't read {}: {e}", path.display()));
+
+        let mut low = Vec::new();
+        let mut high = Vec::new();
+
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() || line.starts_with("hdr") {
+                continue;
+            }
+
+            let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+            let bad = || panic!("{}:{}: expected `hdr, dac, lumens`", path.display(), n + 1);
+            let [hdr, dac, lumens] = fields[..] else {
+                bad()
+            };
+
+            let hdr = hdr.parse::<bool>().unwrap_or_else(|_| bad());
+            let dac = dac.parse::<u16>().unwrap_or_else(|_| bad());
+            let lumens = lumens.parse::<f32>().unwrap_or_else(|_| bad());
+
+            if hdr { &mut high } else { &mut low }.push((dac, lumens));
+        }
+
+        assert!(
+            !low.is_empty() && !high.is_empty(),
+            "{}: needs measurements from both gears",
+            path.display()
+        );
+
+        // interpolating needs distinct codes, and picking the closest level
+        // needs the output to keep rising with the code
+        for (gear, points) in [("low", &mut low), ("high", &mut high)] {
+            points.sort_by_key(|(dac, _)| *dac);
+
+            for pair in points.windows(2) {
+                let [(dac_a, lumens_a), (dac_b, lumens_b)] = pair else {
+                    unreachable!()
+                };
+                if dac_a == dac_b {
+                    panic!(
+                        "{}: dac {dac_a} measured twice in the {gear} gear",
+                        path.display()
+                    );
+                }
+                if lumens_b <= lumens_a {
+                    panic!(
+                        "{}: output in the {gear} gear doesn't rise from dac {dac_a} ({lumens_a} lm) to dac {dac_b} ({lumens_b} lm)",
+                        path.display()
+                    );
+                }
+            }
+        }
+
+        let max_lumens = low.iter().chain(&high).map(|(_, l)| *l).fold(0.0, f32::max);
+
+        Self {
+            path: path.to_owned(),
+            low,
+            high,
+            max_lumens,
+        }
+    }
+
+    fn points(&self, hdr: bool) -> &[(u16, f32)] {
+        if hdr {
+            &self.high
         } else {
-            (1.0 / hdr_factor, 0.0)
+            &self.low
+        }
+    }
+
+    // whether the measurements cover this level, we don't extrapolate past
+    // the highest code measured in each gear
+    fn covers(&self, level: &PowerLevel) -> bool {
+        level.dac <= self.points(level.hdr).last().unwrap().0
+    }
+
+    fn output(&self, level: &PowerLevel) -> f32 {
+        let points = self.points(level.hdr);
+
+        // a DAC code of zero is no output at all
+        let below = points
+            .iter()
+            .rev()
+            .find(|(dac, _)| *dac <= level.dac)
+            .copied()
+            .unwrap_or((0, 0.0));
+        let Some(&above) = points.iter().find(|(dac, _)| *dac > level.dac) else {
+            return below.1 / self.max_lumens;
         };
 
-        scale * (self.dac as f32 / 4096.0) + offset
+        let t = (level.dac - below.0) as f32 / (above.0 - below.0) as f32;
+        (below.1 + (above.1 - below.1) * t) / self.max_lumens
     }
 }
 
-fn possible_levels() -> Vec<PowerLevel> {
+#[derive(Debug)]
+struct CurveConfig {
+    path: PathBuf,
+    levels: usize,
+    curve: Curve,
+    hdr_factor: f32,
+    low_dac_max: u16,
+    high_dac_min: u16,
+    max_current: f32,
+    led_volts_min: f32,
+    led_volts_max: f32,
+    measured: Option<Measured>,
+}
+
+impl CurveConfig {
+    // `key = value` lines, with # comments
+    fn load(path: &Path) -> Self {
+        let src = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("couldn't read {}: {e}", path.display()));
+
+        let mut values = HashMap::new();
+        for (n, line) in src.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                panic!("{}:{}: expected `key = value`", path.display(), n + 1);
+            };
+            values.insert(key.trim().to_owned(), value.trim().to_owned());
+        }
+
+        let get = |key: &str| -> &str {
+            values
+                .get(key)
+                .unwrap_or_else(|| panic!("{}: missing `{key}`", path.display()))
+        };
+        let num = |key: &str| -> f32 {
+            get(key)
+                .parse()
+                .unwrap_or_else(|e| panic!("{}: bad `{key}`: {e}", path.display()))
+        };
+
+        let curve = match get("curve") {
+            "power" => Curve::Power {
+                exponent: num("exponent"),
+            },
+            "exponential" => Curve::Exponential {
+                min_output: num("min_output"),
+            },
+            "table" => Curve::Table {
+                points: get("table")
+                    .split(',')
+                    .map(|p| {
+                        p.trim()
+                            .parse()
+                            .unwrap_or_else(|e| panic!("{}: bad `table`: {e}", path.display()))
+                    })
+                    .collect(),
+            },
+            other => panic!("{}: unknown curve `{other}`", path.display()),
+        };
+
+        if let Curve::Table { points } = &curve {
+            assert!(
+                points.len() >= 2 && points.iter().all(|&p| p > 0.0),
+                "{}: `table` needs at least two points, all above zero",
+                path.display()
+            );
+        }
+
+        // relative to the config file
+        let measured = values.get("measured").map(|m| {
+            let path = path.parent().unwrap_or(Path::new(".")).join(m);
+            println!("cargo:rerun-if-changed={}", path.display());
+            Measured::load(&path)
+        });
+
+        let levels = num("levels") as usize;
+        assert!(levels > 0, "{}: `levels` can't be zero", path.display());
+
+        Self {
+            path: path.to_owned(),
+            levels,
+            curve,
+            hdr_factor: num("hdr_factor"),
+            low_dac_max: num("low_dac_max") as u16,
+            high_dac_min: num("high_dac_min") as u16,
+            max_current: num("max_current"),
+            led_volts_min: num("led_volts_min"),
+            led_volts_max: num("led_volts_max"),
+            measured,
+        }
+    }
+}
+
+fn curve_config_path(manifest_dir: &Path) -> PathBuf {
+    println!("cargo:rerun-if-env-changed=TYRFING_CURVE");
+    if let Some(path) = env::var_os("TYRFING_CURVE") {
+        return PathBuf::from(path);
+    }
+
+    manifest_dir.join("curves").join("default.cfg")
+}
+
+fn possible_levels(config: &CurveConfig) -> Vec<PowerLevel> {
     let levels = [false, true]
         .into_iter()
-        .flat_map(|hdr| (0..4096).map(move |dac| PowerLevel { hdr, dac }))
+        .flat_map(|hdr| (0..DAC_CODES).map(move |dac| PowerLevel { hdr, dac }))
         .filter(|l| l.hdr || l.dac != 0)
-        .filter(|l| !l.hdr || l.dac > 10) // clip off the low end of the high gear
-        .filter(|l| l.hdr || l.dac < 3000) // clip off the upper end of the low gear
+        .filter(|l| !l.hdr || l.dac >= config.high_dac_min)
+        .filter(|l| l.hdr || l.dac < config.low_dac_max)
+        .filter(|l| config.measured.as_ref().map_or(true, |m| m.covers(l)))
         .collect::<Vec<_>>();
 
     levels
 }
 
-fn main() {
-    let power_levels = 256usize;
-
-    let possible_levels = possible_levels();
+// the level picked for one entry of the table
+struct Selected {
+    target: f32,
+    level: PowerLevel,
+    alt: Option<u16>,
+}
 
-    let selected_levels = (1..=power_levels).map(|l| {
-        let l = l as f32 / power_levels as f32;
-        let l = l.powi(4);
+fn select_levels(config: &CurveConfig) -> Vec<Selected> {
+    let possible_levels = possible_levels(config);
 
-        let PowerLevel { hdr, dac } = possible_levels
+    // the crossover runs between the top of the low range and the bottom of
+    // the high range, whichever way round they are. Levels in there can be
+    // had from either range, or are as close as each gets
+    let outputs = |hdr: bool| {
+        possible_levels
             .iter()
-            .min_by(|a, b| f32::total_cmp(&(a.output() - l).abs(), &(b.output() - l).abs()))
-            .unwrap();
+            .filter(move |p| p.hdr == hdr)
+            .map(|p| p.output(config))
+    };
+    let low_top = outputs(false).fold(f32::MIN, f32::max);
+    let high_bottom = outputs(true).fold(f32::MAX, f32::min);
+    let crossover = low_top.min(high_bottom)..=low_top.max(high_bottom);
+
+    let selected = (1..=config.levels)
+        .map(|i| {
+            let l = config.curve.output(i as f32 / config.levels as f32);
 
-        println!("cargo:warning=For {l:0.3}: hdr: {hdr}, dac: {dac}");
+            let closest = |hdr: bool| {
+                possible_levels
+                    .iter()
+                    .filter(|p| p.hdr == hdr)
+                    .min_by(|a, b| {
+                        f32::total_cmp(&(a.output(config) - l).abs(), &(b.output(config) - l).abs())
+                    })
+                    .unwrap()
+            };
 
-        quote! {
-            PowerLevel {
-                hdr: #hdr,
-                dac: #dac,
+            let (low, high) = (closest(false), closest(true));
+            let error = |p: &PowerLevel| (p.output(config) - l).abs();
+            let (level, other) = if error(low) <= error(high) {
+                (low, high)
+            } else {
+                (high, low)
+            };
+
+            // in the crossover the driver stays in whichever range it's
+            // already in, which gives it some hysteresis
+            let alt = crossover.contains(&l).then_some(other.dac);
+
+            Selected {
+                target: l,
+                level: PowerLevel {
+                    hdr: level.hdr,
+                    dac: level.dac,
+                },
+                alt,
             }
+        })
+        .collect::<Vec<_>>();
+
+    check_monotonic(config, &selected);
+
+    selected
+}
+
+// going up a level must never get dimmer, whichever range the driver is
+// holding on to through the crossover
+fn check_monotonic(config: &CurveConfig, selected: &[Selected]) {
+    for (name, range) in [("either", None), ("low", Some(false)), ("high", Some(true))] {
+        let output = |s: &Selected| match (range, s.alt) {
+            (Some(hdr), Some(dac)) if hdr != s.level.hdr => PowerLevel { hdr, dac }.output(config),
+            _ => s.level.output(config),
+        };
+
+        for (i, pair) in selected.windows(2).enumerate() {
+            let (below, above) = (output(&pair[0]), output(&pair[1]));
+            assert!(
+                above >= below,
+                "{}: level {} is dimmer than level {} staying in {name} range ({above} < {below})",
+                config.path.display(),
+                i + 2,
+                i + 1,
+            );
         }
-    });
+    }
+}
+
+// the fixture is a made up emitter, measured linear between a few points so
+// the codes below can be worked out by hand. if this trips the selection or
+// the interpolation of measurements changed
+fn check_measured_fixture(manifest_dir: &Path) {
+    let path = manifest_dir.join("curves/fixtures/measured.cfg");
+    println!("cargo:rerun-if-changed={}", path.display());
+
+    // (hdr, dac, alt_dac)
+    const EXPECTED: [(bool, u16, Option<u16>); 3] = [
+        (false, 1500, Some(167)),
+        (true, 1766, None),
+        (true, 4000, None),
+    ];
+
+    let selected = select_levels(&CurveConfig::load(&path))
+        .iter()
+        .map(|s| (s.level.hdr, s.level.dac, s.alt))
+        .collect::<Vec<_>>();
+
+    assert!(
+        selected == EXPECTED,
+        "{}: picked {selected:?}, expected {EXPECTED:?}",
+        path.display()
+    );
+}
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    check_measured_fixture(&manifest_dir);
+
+    let config_path = curve_config_path(&manifest_dir);
+    println!("cargo:rerun-if-changed={}", config_path.display());
+
+    let config = CurveConfig::load(&config_path);
+    let power_levels = config.levels;
+
+    let mut report = String::new();
+    writeln!(report, "power curve from {}", config.path.display()).unwrap();
+    match &config.measured {
+        Some(m) => writeln!(
+            report,
+            "{:?}, measured output from {} ({} lm max)",
+            config.curve,
+            m.path.display(),
+            m.max_lumens
+        ),
+        None => writeln!(
+            report,
+            "{:?}, hdr factor {}",
+            config.curve, config.hdr_factor
+        ),
+    }
+    .unwrap();
+    writeln!(report).unwrap();
+    writeln!(report, "level     target     actual  hdr   dac   alt").unwrap();
+
+    let selected_levels = select_levels(&config)
+        .iter()
+        .enumerate()
+        .map(|(i, Selected { target, level, alt })| {
+            writeln!(
+                report,
+                "{:>5} {target:>10.7} {:>10.7} {:>5} {:>5} {:>5}",
+                i + 1,
+                level.output(&config),
+                level.hdr,
+                level.dac,
+                alt.map_or(String::from("-"), |a| a.to_string()),
+            )
+            .unwrap();
+
+            let power_mw = (level.power(&config) * 1000.0).round() as u16;
+            let PowerLevel { hdr, dac } = level;
+            let alt_dac = match alt {
+                Some(a) => quote! { Some(#a) },
+                None => quote! { None },
+            };
+            quote! {
+                PowerLevel {
+                    hdr: #hdr,
+                    dac: #dac,
+                    alt_dac: #alt_dac,
+                    power_mw: #power_mw,
+                }
+            }
+        })
+        .collect::<Vec<_>>();
 
     let mut g = TokenStream::new();
     g.extend(quote! {
         pub struct PowerLevel {
             pub hdr: bool,
             pub dac: u16,
+            /// DAC code giving about the same output in the other range.
+            pub alt_dac: Option<u16>,
+            /// Estimated power into the LEDs, in milliwatts.
+            pub power_mw: u16,
         }
 
         pub const POWER_LEVELS: [PowerLevel; #power_levels] = [
@@ -81,6 +499,13 @@ fn main() {
 
     fs::write(&dest_path, g.to_string()).unwrap();
 
+    let report_path = Path::new(&out_dir).join("power_curve_report.txt");
+    fs::write(&report_path, report).unwrap();
+    println!(
+        "cargo:warning=Power curve report written to {}",
+        report_path.display()
+    );
+
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
@@ -0,0 +1,398 @@
+// Runs the firmware's UI, power, aux and monitoring tasks against a virtual
+// flashlight, driven by a script of button presses given on the command line.
+//
+// Time is virtual: whenever every task is idle the clock jumps straight to the
+// next timer deadline, so long scripts run instantly and the output is the
+// same on every run.
+//
+//   cargo run -- click wait:1000 hold:2000 wait:500 clicks:4
+//
+// steps:
+//   click        press and release the button
+//   clicks:N     N clicks in quick succession
+//   hold:MS      hold the button down for MS milliseconds
+//   wait:MS      leave the button alone for MS milliseconds
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering},
+    Mutex,
+};
+
+use cichlid::ColorRGB;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
+use fixed::types::I16F16;
+use maitake::{
+    scheduler::StaticScheduler,
+    time::{Clock, Duration, Timer},
+};
+use tyrfing_stm::{
+    aux::Rgb1Bit,
+    hal,
+    monitoring::{Temp, Voltage},
+    settings::{self, MemEeprom},
+};
+
+static SCHEDULER: StaticScheduler = maitake::scheduler::new_static!();
+
+static NOW_MS: AtomicU64 = AtomicU64::new(0);
+static SCRIPT_DONE: AtomicBool = AtomicBool::new(false);
+
+fn now_ms() -> u64 {
+    NOW_MS.load(Ordering::Relaxed)
+}
+
+macro_rules! log {
+    ($($arg:tt)*) => {
+        println!("[{:>5}.{:03}] {}", now_ms() / 1000, now_ms() % 1000, format_args!($($arg)*))
+    };
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+// defmt frames are binary encoded and need the ELF to decode, the simulator
+// reports what it sees through its own logging instead
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+defmt::timestamp!("{=u64:ms}", now_ms());
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+// the virtual flashlight
+
+const AMBIENT_TEMP: f32 = 22.0;
+const BATTERY_VOLTS: f32 = 3.9;
+
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+static HEAD_TEMP: Mutex<f32> = Mutex::new(AMBIENT_TEMP);
+
+static PRESSED: AtomicBool = AtomicBool::new(false);
+static BUTTON_CHANGED: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+struct VirtualPower;
+
+impl hal::PowerDriver for VirtualPower {
+    type Paths<'a> = VirtualPaths;
+
+    fn bring_up(&mut self) -> VirtualPaths {
+        log!("power paths up");
+        VirtualPaths
+    }
+}
+
+struct VirtualPaths;
+
+impl hal::PowerPaths for VirtualPaths {
+    async fn set(&mut self, level: u8) {
+        let old = LEVEL.swap(level, Ordering::Relaxed);
+        if old != level {
+            log!("level {:>3} -> {:>3}", old, level);
+        }
+    }
+}
+
+impl Drop for VirtualPaths {
+    fn drop(&mut self) {
+        LEVEL.store(0, Ordering::Relaxed);
+        log!("power paths down");
+    }
+}
+
+#[derive(Default)]
+struct VirtualAux {
+    last: Option<(u8, u8, u8)>,
+    last_print: u64,
+}
+
+impl hal::AuxLeds for VirtualAux {
+    type Pwm<'a> = VirtualAuxPwm<'a>;
+    type Low<'a> = VirtualAuxLow<'a>;
+
+    fn pwm(&mut self) -> VirtualAuxPwm<'_> {
+        log!("aux: pwm mode");
+        self.last = None;
+        VirtualAuxPwm(self)
+    }
+
+    fn low(&mut self) -> VirtualAuxLow<'_> {
+        log!("aux: low power mode");
+        self.last = None;
+        VirtualAuxLow(self)
+    }
+}
+
+struct VirtualAuxPwm<'a>(&'a mut VirtualAux);
+
+impl hal::AuxPwm for VirtualAuxPwm<'_> {
+    fn set(&mut self, c: ColorRGB) {
+        let c = (c.r, c.g, c.b);
+
+        // the aux animations update every 16ms, only report them now and then
+        if self.0.last != Some(c) && now_ms() >= self.0.last_print + 250 {
+            log!("aux: rgb({}, {}, {})", c.0, c.1, c.2);
+            self.0.last = Some(c);
+            self.0.last_print = now_ms();
+        }
+    }
+}
+
+struct VirtualAuxLow<'a>(&'a mut VirtualAux);
+
+impl hal::AuxLow for VirtualAuxLow<'_> {
+    fn set(&mut self, c: Rgb1Bit) {
+        let c = (c.r as u8, c.g as u8, c.b as u8);
+
+        if self.0.last != Some(c) {
+            log!("aux: low r: {} g: {} b: {}", c.0, c.1, c.2);
+            self.0.last = Some(c);
+        }
+    }
+}
+
+struct VirtualButton;
+
+impl hal::Button for VirtualButton {
+    async fn wait_for_press(&mut self) {
+        while !PRESSED.load(Ordering::Relaxed) {
+            BUTTON_CHANGED.wait().await;
+        }
+    }
+
+    fn is_pressed(&mut self) -> bool {
+        PRESSED.load(Ordering::Relaxed)
+    }
+}
+
+// flickers with the software PWM, too noisy to log
+struct VirtualButtonLed;
+
+impl hal::ButtonLed for VirtualButtonLed {
+    fn set(&mut self, _on: bool) {}
+}
+
+struct VirtualSensors;
+
+impl hal::Sensors for VirtualSensors {
+    type Session<'a> = VirtualSensors;
+
+    async fn session(&mut self) -> VirtualSensors {
+        VirtualSensors
+    }
+}
+
+impl hal::SensorSession for VirtualSensors {
+    async fn voltage(&mut self) -> Voltage {
+        Voltage(I16F16::from_num(BATTERY_VOLTS))
+    }
+
+    async fn temp(&mut self) -> Temp {
+        Temp(I16F16::from_num(*HEAD_TEMP.lock().unwrap()))
+    }
+}
+
+struct VirtualSupervisor;
+
+impl hal::Supervisor for VirtualSupervisor {
+    fn pet_watchdog(&mut self) {}
+
+    fn emergency_stop(&mut self) -> ! {
+        log!("EMERGENCY STOP");
+        std::process::exit(1);
+    }
+}
+
+// first order thermal model, the head heats up towards an equilibrium set by
+// the output level
+async fn thermal_model() {
+    const TIME_CONSTANT_S: f32 = 60.0;
+    const DEGREES_AT_FULL_POWER: f32 = 50.0;
+    const STEP_MS: u64 = 100;
+
+    loop {
+        maitake::time::sleep(Duration::from_millis(STEP_MS)).await;
+
+        let output = LEVEL.load(Ordering::Relaxed) as f32 / 255.0;
+        let target = AMBIENT_TEMP + DEGREES_AT_FULL_POWER * output.powi(2);
+
+        let mut t = HEAD_TEMP.lock().unwrap();
+        *t += (target - *t) * (STEP_MS as f32 / 1000.0) / TIME_CONSTANT_S;
+    }
+}
+
+async fn state_monitor() {
+    let mut last = None;
+
+    loop {
+        let state = (
+            tyrfing_stm::state::is_on().await,
+            tyrfing_stm::state::is_unlocked().await,
+        );
+
+        if last != Some(state) {
+            log!(
+                "state: {}, {}",
+                if state.0 { "on" } else { "off" },
+                if state.1 { "unlocked" } else { "locked" }
+            );
+            last = Some(state);
+        }
+
+        maitake::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[derive(Debug)]
+enum Step {
+    Press,
+    Release,
+    Wait(u64),
+}
+
+const CLICK_MS: u64 = 80;
+
+fn parse_script(args: impl Iterator<Item = String>) -> Result<Vec<Step>, String> {
+    let mut steps = Vec::new();
+
+    for arg in args {
+        let (cmd, value) = match arg.split_once(':') {
+            Some((cmd, value)) => {
+                let value = value
+                    .parse::<u64>()
+                    .map_err(|e| format!("bad number in {arg:?}: {e}"))?;
+                (cmd, Some(value))
+            }
+            None => (arg.as_str(), None),
+        };
+
+        match (cmd, value) {
+            ("click", None) => {
+                steps.extend([Step::Press, Step::Wait(CLICK_MS), Step::Release]);
+                steps.push(Step::Wait(CLICK_MS));
+            }
+            ("clicks", Some(n)) => {
+                for _ in 0..n {
+                    steps.extend([Step::Press, Step::Wait(CLICK_MS), Step::Release]);
+                    steps.push(Step::Wait(CLICK_MS));
+                }
+            }
+            ("hold", Some(ms)) => {
+                steps.extend([Step::Press, Step::Wait(ms), Step::Release]);
+                steps.push(Step::Wait(CLICK_MS));
+            }
+            ("wait", Some(ms)) => steps.push(Step::Wait(ms)),
+            _ => return Err(format!("unknown step {arg:?}")),
+        }
+    }
+
+    Ok(steps)
+}
+
+async fn run_script(steps: Vec<Step>) {
+    // give everything a moment to come up
+    maitake::time::sleep(Duration::from_millis(100)).await;
+
+    for step in steps {
+        match step {
+            Step::Press => {
+                log!("button: press");
+                PRESSED.store(true, Ordering::Relaxed);
+                BUTTON_CHANGED.signal(());
+            }
+            Step::Release => {
+                log!("button: release");
+                PRESSED.store(false, Ordering::Relaxed);
+                BUTTON_CHANGED.signal(());
+            }
+            Step::Wait(ms) => maitake::time::sleep(Duration::from_millis(ms)).await,
+        }
+    }
+
+    // let anything the script triggered play out
+    maitake::time::sleep(Duration::from_secs(5)).await;
+
+    SCRIPT_DONE.store(true, Ordering::Relaxed);
+}
+
+#[repr(transparent)]
+struct SurelySend<T>(T);
+// everything runs on the one thread
+unsafe impl<T> Send for SurelySend<T> {}
+impl<T: core::future::Future> core::future::Future for SurelySend<T> {
+    type Output = <T as core::future::Future>::Output;
+
+    fn poll(
+        self: core::pin::Pin<&mut Self>,
+        cx: &mut core::task::Context<'_>,
+    ) -> core::task::Poll<Self::Output> {
+        unsafe { self.map_unchecked_mut(|s| &mut s.0) }.poll(cx)
+    }
+}
+
+fn main() {
+    let steps = match parse_script(std::env::args().skip(1)) {
+        Ok(steps) => steps,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(2);
+        }
+    };
+
+    let clock = Clock::new(Duration::from_millis(1), now_ms);
+    let timer: &'static Timer = Box::leak(Box::new(Timer::new(clock)));
+    maitake::time::set_global_timer(timer).unwrap();
+
+    let mut settings_store = settings::Store::new(MemEeprom::<256>::new());
+    settings::init(settings_store.load());
+
+    SCHEDULER.spawn(SurelySend(tyrfing_stm::monitoring::monitoring_task(
+        VirtualSensors,
+        VirtualSupervisor,
+    )));
+    SCHEDULER.spawn(SurelySend(tyrfing_stm::power::power_task(VirtualPower)));
+    SCHEDULER.spawn(SurelySend(
+        tyrfing_stm::aux::aux_task(VirtualAux::default()),
+    ));
+    SCHEDULER.spawn(SurelySend(tyrfing_stm::click::debouncer_task(
+        VirtualButton,
+    )));
+    SCHEDULER.spawn(SurelySend(tyrfing_stm::button_led::button_led_task(
+        VirtualButtonLed,
+    )));
+    SCHEDULER.spawn(SurelySend(tyrfing_stm::click::event_generator_task()));
+    SCHEDULER.spawn(SurelySend(tyrfing_stm::click::button_logger_task()));
+    SCHEDULER.spawn(SurelySend(settings::settings_task(settings_store)));
+    SCHEDULER.spawn(SurelySend(tyrfing_stm::ui::torch_ui_task()));
+    SCHEDULER.spawn(SurelySend(thermal_model()));
+    SCHEDULER.spawn(SurelySend(state_monitor()));
+    SCHEDULER.spawn(SurelySend(run_script(steps)));
+
+    loop {
+        timer.turn();
+
+        if SCHEDULER.tick().has_remaining {
+            continue;
+        }
+
+        if SCRIPT_DONE.load(Ordering::Relaxed) {
+            break;
+        }
+
+        // everything is waiting on a timer, skip ahead to the next one
+        match timer.turn().ticks_to_next_deadline() {
+            Some(ticks) => {
+                NOW_MS.fetch_add(ticks.max(1), Ordering::Relaxed);
+            }
+            None => break,
+        }
+    }
+
+    log!("done");
+}
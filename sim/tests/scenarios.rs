@@ -0,0 +1,151 @@
+// Runs the simulator on button scripts and checks the timeline it prints.
+//
+// A script starts 100ms in and each click takes 160ms (80ms down, 80ms up),
+// clicks and holds are recognised 300ms after the button last changed. The
+// times picked below leave room either side of those.
+
+use std::process::Command;
+
+struct Timeline {
+    // (ms, level) for every change of output level
+    levels: Vec<(u64, u8)>,
+    // (ms, on, unlocked) for every change of state
+    states: Vec<(u64, bool, bool)>,
+    stdout: String,
+}
+
+impl Timeline {
+    fn level_at(&self, ms: u64) -> u8 {
+        self.levels
+            .iter()
+            .take_while(|&&(at, _)| at <= ms)
+            .last()
+            .map_or(0, |&(_, level)| level)
+    }
+
+    fn state_at(&self, ms: u64) -> (bool, bool) {
+        self.states
+            .iter()
+            .take_while(|&&(at, _, _)| at <= ms)
+            .last()
+            .map(|&(_, on, unlocked)| (on, unlocked))
+            .unwrap_or_else(|| panic!("no state by {ms}ms\n{}", self.stdout))
+    }
+
+    fn last_state(&self) -> (bool, bool) {
+        self.state_at(u64::MAX)
+    }
+
+    fn was_ever_on(&self) -> bool {
+        self.states.iter().any(|&(_, on, _)| on)
+    }
+}
+
+fn sim(script: &str) -> Timeline {
+    let output = Command::new(env!("CARGO_BIN_EXE_tyrfing-sim"))
+        .args(script.split_whitespace())
+        .output()
+        .expect("failed to run the simulator");
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(output.status.success(), "simulator failed:\n{stdout}");
+
+    let mut timeline = Timeline {
+        levels: Vec::new(),
+        states: Vec::new(),
+        stdout: stdout.clone(),
+    };
+
+    // lines look like `[    1.960] level   0 ->  27`
+    for line in stdout.lines() {
+        let Some((time, msg)) = line.strip_prefix('[').and_then(|l| l.split_once("] ")) else {
+            continue;
+        };
+        let (secs, ms) = time.trim().split_once('.').unwrap();
+        let at = secs.parse::<u64>().unwrap() * 1000 + ms.parse::<u64>().unwrap();
+
+        if let Some(change) = msg.strip_prefix("level ") {
+            let level = change.split_whitespace().last().unwrap().parse().unwrap();
+            timeline.levels.push((at, level));
+        } else if msg == "power paths down" {
+            timeline.levels.push((at, 0));
+        } else if let Some(state) = msg.strip_prefix("state: ") {
+            let (on, lock) = state.split_once(", ").unwrap();
+            timeline.states.push((at, on == "on", lock == "unlocked"));
+        }
+    }
+
+    timeline
+}
+
+// three clicks from a fresh EEPROM, done by 580ms
+const UNLOCK: &str = "clicks:3";
+
+#[test]
+fn starts_locked_and_unlocks_with_three_clicks() {
+    let t = sim(UNLOCK);
+
+    assert_eq!(t.state_at(50), (false, false));
+    assert_eq!(t.last_state(), (false, true));
+    assert!(!t.was_ever_on());
+}
+
+#[test]
+fn click_turns_on_and_off() {
+    // click at 1580 comes on at ~1960, click at 3740 goes off at ~4120
+    let t = sim(&format!("{UNLOCK} wait:1000 click wait:2000 click"));
+
+    assert_eq!(t.state_at(1500), (false, true));
+    assert_eq!(t.state_at(3000), (true, true));
+    assert_eq!(t.level_at(3000), 27);
+
+    assert_eq!(t.last_state(), (false, true));
+    assert_eq!(t.level_at(u64::MAX), 0);
+}
+
+#[test]
+fn holding_ramps_up() {
+    // on at ~1960, held from 2740 to 4740
+    let t = sim(&format!("{UNLOCK} wait:1000 click wait:1000 hold:2000"));
+
+    let before = t.level_at(2700);
+    let after = t.level_at(6000);
+    assert_eq!(before, 27);
+    assert!(after > before + 50, "only ramped to {after}");
+
+    // and it kept going up the whole time it was held
+    let during = t.level_at(3800);
+    assert!(before < during && during < after);
+}
+
+#[test]
+fn comes_back_on_at_the_last_level() {
+    // ramp up, off at ~6700, back on at ~8860
+    let t = sim(&format!(
+        "{UNLOCK} wait:1000 click wait:1000 hold:2000 wait:1500 click wait:2000 click"
+    ));
+
+    let ramped = t.level_at(6000);
+    assert!(ramped > 27);
+    assert_eq!(t.state_at(8000), (false, true));
+    assert_eq!(t.level_at(8000), 0);
+    assert_eq!(t.state_at(10000), (true, true));
+    assert_eq!(t.level_at(10000), ramped);
+}
+
+#[test]
+fn four_clicks_lock_again() {
+    // four clicks from 1580, then a click at ~3220 that shouldn't turn it on
+    let t = sim(&format!("{UNLOCK} wait:1000 clicks:4 wait:1000 click"));
+
+    assert_eq!(t.state_at(1500), (false, true));
+    assert_eq!(t.last_state(), (false, false));
+    assert!(!t.was_ever_on());
+}
+
+#[test]
+fn runs_are_repeatable() {
+    let script = format!("{UNLOCK} wait:1000 click wait:1000 hold:2000");
+
+    assert_eq!(sim(&script).stdout, sim(&script).stdout);
+}
@@ -1,21 +1,14 @@
 use cichlid::ColorRGB;
-use defmt::debug;
-use embassy_stm32::{
-    gpio::{Flex, OutputType, Pull},
-    peripherals::{PA6, PA7, PB0, TIM3},
-    time::Hertz,
-    timer::{
-        low_level::CountingMode,
-        simple_pwm::{PwmPin, SimplePwm},
-        Channel,
-    },
-    Peripheral as _,
-};
 use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
 use fixed::types::{extra::U16, I16F16};
 use fixed_macro::types::I16F16;
+use maitake::time::{Duration, Instant};
 
-use crate::monitoring::{Temp, Voltage};
+use crate::{
+    battery_level::BatteryProfile,
+    hal::{AuxLeds, AuxLow, AuxPwm},
+    monitoring::{Temp, Voltage},
+};
 
 static POKE_AUX: embassy_sync::signal::Signal<ThreadModeRawMutex, ()> =
     embassy_sync::signal::Signal::new();
@@ -25,10 +18,10 @@ pub fn poke_aux() {
 }
 
 #[derive(Clone, Copy)]
-struct Rgb1Bit {
-    r: bool,
-    g: bool,
-    b: bool,
+pub struct Rgb1Bit {
+    pub r: bool,
+    pub g: bool,
+    pub b: bool,
 }
 
 impl Rgb1Bit {
@@ -47,52 +40,7 @@ impl Rgb1Bit {
     }
 }
 
-struct AuxPwm<'a> {
-    pwm: SimplePwm<'a, TIM3>,
-}
-
-impl<'a> AuxPwm<'a> {
-    fn set(&mut self, c: ColorRGB) {
-        let max_duty = self.pwm.get_max_duty();
-
-        let calc_duty = |v: u8| {
-            let d = (v as u32 * max_duty) / 255;
-
-            debug!("colour: {}, max_duty: {}, duty: {}", v, max_duty, d);
-
-            d
-        };
-
-        for (c, v) in [
-            (Channel::Ch1, c.r),
-            (Channel::Ch2, c.g),
-            (Channel::Ch3, c.b),
-        ] {
-            if v != 0 {
-                self.pwm.enable(c);
-            } else {
-                self.pwm.disable(c);
-            }
-            self.pwm.set_duty(c, calc_duty(v));
-        }
-    }
-}
-
-struct AuxLow<'a> {
-    r: Flex<'a>,
-    g: Flex<'a>,
-    b: Flex<'a>,
-}
-
-impl<'a> AuxLow<'a> {
-    fn set(&mut self, c: Rgb1Bit) {
-        self.r.set_as_input(if c.r { Pull::Up } else { Pull::Down });
-        self.g.set_as_input(if c.g { Pull::Up } else { Pull::Down });
-        self.b.set_as_input(if c.b { Pull::Up } else { Pull::Down });
-    }
-}
-
-async fn transition_to_pwm<'a>(leds: &mut AuxPwm<'a>, prior: ColorRGB, target: ColorRGB) {
+async fn transition_to_pwm(leds: &mut impl AuxPwm, prior: ColorRGB, target: ColorRGB) {
     leds.set(prior);
 
     for i in (0..255u8).step_by(20) {
@@ -108,7 +56,7 @@ fn hue_to_rgb(hue: u8) -> ColorRGB {
     cichlid::HSV::new(hue, 255, 255).to_rgb_rainbow()
 }
 
-async fn rainbow_aux<'a>(leds: &mut AuxPwm<'a>, prior: ColorRGB) -> ColorRGB {
+async fn rainbow_aux(leds: &mut impl AuxPwm, prior: ColorRGB) -> ColorRGB {
     let mut h = 0u8;
     let target_startup_colour = hue_to_rgb(h);
 
@@ -129,13 +77,13 @@ async fn rainbow_aux<'a>(leds: &mut AuxPwm<'a>, prior: ColorRGB) -> ColorRGB {
     }
 }
 
-fn volts_to_rgb(volts: Voltage) -> ColorRGB {
+fn volts_to_rgb(profile: &BatteryProfile, volts: Voltage) -> ColorRGB {
     // red
     let min_hue = 0u8;
     // magenta
     let max_hue = 212u8;
 
-    let level = crate::battery_level::voltage_to_level(volts.0);
+    let level = profile.voltage_to_level(volts);
 
     let hue = level
         .lerp(I16F16::from_num(min_hue), I16F16::from_num(max_hue))
@@ -160,31 +108,53 @@ fn temp_to_rgb(temp: Temp) -> ColorRGB {
     hue_to_rgb(hue)
 }
 
-fn volts_to_1bit_rgb(volts: Voltage) -> Rgb1Bit {
-    if volts > Voltage(I16F16!(4.1)) {
+fn volts_to_1bit_rgb(profile: &BatteryProfile, volts: Voltage) -> Rgb1Bit {
+    // these were 4.1, 3.9, 3.7, 3.5 and 3.3 volts on the li-ion curve
+    let level = profile.voltage_to_level(volts);
+
+    if level > I16F16!(0.94) {
         Rgb1Bit::new(true, false, true)
-    } else if volts > Voltage(I16F16!(3.9)) {
+    } else if level > I16F16!(0.7) {
         Rgb1Bit::new(false, false, true)
-    } else if volts > Voltage(I16F16!(3.7)) {
+    } else if level > I16F16!(0.47) {
         Rgb1Bit::new(false, true, true)
-    } else if volts > Voltage(I16F16!(3.5)) {
+    } else if level > I16F16!(0.27) {
         Rgb1Bit::new(false, true, false)
-    } else if volts > Voltage(I16F16!(3.3)) {
+    } else if level > I16F16!(0.13) {
         Rgb1Bit::new(true, true, false)
     } else {
         Rgb1Bit::new(true, false, false)
     }
 }
 
-async fn voltage_high_aux<'a>(leds: &mut AuxPwm<'a>, prior: ColorRGB) -> ColorRGB {
-    let volts = *crate::monitoring::VOLTAGE.lock().await;
-    let target_startup_colour = volts_to_rgb(volts);
+async fn idle_colour(profile: &BatteryProfile) -> ColorRGB {
+    match crate::settings::get().await.aux_colour {
+        0 => volts_to_rgb(profile, *crate::monitoring::RESTING_VOLTAGE.lock().await),
+        n => hue_to_rgb((n - 1).wrapping_mul(32)),
+    }
+}
+
+// how long before auto-locking the aux starts flashing
+const AUTOLOCK_WARNING: Duration = Duration::from_secs(10);
+
+// the time left before auto-locking, once it's close enough to warn about
+async fn autolock_warning() -> Option<Duration> {
+    let remaining = crate::state::autolock_deadline()
+        .await?
+        .checked_duration_since(Instant::now())
+        .unwrap_or(Duration::ZERO);
+
+    (remaining < AUTOLOCK_WARNING).then_some(remaining)
+}
+
+async fn voltage_high_aux(leds: &mut impl AuxPwm, prior: ColorRGB) -> ColorRGB {
+    let profile = crate::battery_level::profile().await;
+    let target_startup_colour = idle_colour(profile).await;
 
     transition_to_pwm(leds, prior, target_startup_colour).await;
 
     loop {
-        let volts = *crate::monitoring::VOLTAGE.lock().await;
-        let rgb = volts_to_rgb(volts);
+        let rgb = idle_colour(profile).await;
 
         // let temp= *crate::monitoring::TEMP.lock().await;
         // let rgb = temp_to_rgb(temp);
@@ -193,59 +163,60 @@ async fn voltage_high_aux<'a>(leds: &mut AuxPwm<'a>, prior: ColorRGB) -> ColorRG
             return rgb;
         }
 
-        leds.set(rgb);
+        if autolock_warning()
+            .await
+            .is_some_and(|r| r.as_millis() / 250 % 2 == 0)
+        {
+            leds.set(ColorRGB::Black);
+        } else {
+            leds.set(rgb);
+        }
 
         maitake::time::sleep(core::time::Duration::from_millis(64)).await;
     }
 }
 
-async fn transition_to_low_voltage_aux<'a>(leds: &mut AuxPwm<'a>, prior: ColorRGB) -> ColorRGB {
-    let volts = *crate::monitoring::VOLTAGE.lock().await;
-    let target_startup_colour = volts_to_1bit_rgb(volts).to_colorrgb();
+async fn transition_to_low_voltage_aux(leds: &mut impl AuxPwm, prior: ColorRGB) -> ColorRGB {
+    let profile = crate::battery_level::profile().await;
+    let volts = *crate::monitoring::RESTING_VOLTAGE.lock().await;
+    let target_startup_colour = volts_to_1bit_rgb(profile, volts).to_colorrgb();
 
     transition_to_pwm(leds, prior, target_startup_colour).await;
 
     target_startup_colour
 }
 
-async fn voltage_low_aux<'a>(leds: &mut AuxLow<'a>) -> ColorRGB {
+async fn voltage_low_aux(leds: &mut impl AuxLow) -> ColorRGB {
+    // pressing the button shows the voltage right away instead of at the next
+    // refresh
+    let mut states = crate::click::subscribe_states();
+
     loop {
-        let volts = *crate::monitoring::VOLTAGE.lock().await;
-        let rgb = volts_to_1bit_rgb(volts);
+        let profile = crate::battery_level::profile().await;
+        let volts = *crate::monitoring::RESTING_VOLTAGE.lock().await;
+        let rgb = volts_to_1bit_rgb(profile, volts);
         leds.set(rgb);
 
         if crate::state::is_unlocked().await {
             return rgb.to_colorrgb();
         }
 
-        embassy_futures::select::select(
+        embassy_futures::select::select3(
             maitake::time::sleep(core::time::Duration::from_secs(4)),
             POKE_AUX.wait(),
+            states.next(),
         )
         .await;
     }
 }
 
 // #[embassy_executor::task]
-pub async fn aux_task(timer: TIM3, r: PA6, g: PA7, b: PB0) {
-    let mut timer = timer.into_ref();
-    let mut r = r.into_ref();
-    let mut g = g.into_ref();
-    let mut b = b.into_ref();
+pub async fn aux_task(mut leds: impl AuxLeds) {
     let mut prior_colour = ColorRGB::Black;
 
     loop {
         if crate::state::is_unlocked().await {
-            let pwm = SimplePwm::new(
-                timer.reborrow(),
-                Some(PwmPin::new_ch1(r.reborrow(), OutputType::PushPull)),
-                Some(PwmPin::new_ch2(g.reborrow(), OutputType::PushPull)),
-                Some(PwmPin::new_ch3(b.reborrow(), OutputType::PushPull)),
-                None,
-                Hertz::khz(5),
-                CountingMode::EdgeAlignedUp,
-            );
-            let mut aux = AuxPwm { pwm };
+            let mut aux = leds.pwm();
 
             loop {
                 if !crate::state::is_unlocked().await {
@@ -261,11 +232,7 @@ pub async fn aux_task(timer: TIM3, r: PA6, g: PA7, b: PB0) {
 
             prior_colour = transition_to_low_voltage_aux(&mut aux, prior_colour).await;
         } else {
-            let mut aux = AuxLow {
-                r: Flex::new(r.reborrow()),
-                g: Flex::new(g.reborrow()),
-                b: Flex::new(b.reborrow()),
-            };
+            let mut aux = leds.low();
 
             prior_colour = voltage_low_aux(&mut aux).await;
         }
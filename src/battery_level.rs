@@ -1,6 +1,8 @@
 use fixed::types::I16F16;
 use fixed_macro::types::I16F16;
 
+use crate::monitoring::Voltage;
+
 static POINTS: [I16F16; 10] = [
     I16F16!(0.0),
     I16F16!(0.111111),
@@ -13,29 +15,148 @@ static POINTS: [I16F16; 10] = [
     I16F16!(0.888888),
     I16F16!(0.999999),
 ];
-static VOLTAGES: [I16F16; 10] = [
-    I16F16!(3.0),
-    I16F16!(3.25),
-    I16F16!(3.45),
-    I16F16!(3.57),
-    I16F16!(3.68),
-    I16F16!(3.77),
-    I16F16!(3.85),
-    I16F16!(3.97),
-    I16F16!(4.05),
-    I16F16!(4.15),
-];
 
-pub fn voltage_to_level(voltage: I16F16) -> I16F16 {
-    let idx = VOLTAGES.binary_search(&voltage).map_or_else(|x| x, |x| x);
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Chemistry {
+    LiIon,
+    LiFePO4,
+    LiHv,
+}
+
+impl Chemistry {
+    pub const ALL: [Chemistry; 3] = [Chemistry::LiIon, Chemistry::LiFePO4, Chemistry::LiHv];
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Chemistry::LiIon => 0,
+            Chemistry::LiFePO4 => 1,
+            Chemistry::LiHv => 2,
+        }
+    }
 
-    if idx >= POINTS.len() - 1 {
-        return I16F16!(1.0);
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.to_u8() == v)
     }
 
-    let (v_low, v_high) = (VOLTAGES[idx], VOLTAGES[idx + 1]);
-    let (lvl_low, lvl_high) = (POINTS[idx], POINTS[idx + 1]);
+    pub fn profile(self) -> &'static BatteryProfile {
+        match self {
+            Chemistry::LiIon => &LI_ION,
+            Chemistry::LiFePO4 => &LI_FE_PO4,
+            Chemistry::LiHv => &LI_HV,
+        }
+    }
+}
+
+#[cfg(all(feature = "battery_lifepo4", feature = "battery_lihv"))]
+compile_error!("only one of the battery_lifepo4 and battery_lihv features can be enabled");
+
+#[cfg(not(any(feature = "battery_lifepo4", feature = "battery_lihv")))]
+pub const DEFAULT_CHEMISTRY: Chemistry = Chemistry::LiIon;
+#[cfg(feature = "battery_lifepo4")]
+pub const DEFAULT_CHEMISTRY: Chemistry = Chemistry::LiFePO4;
+#[cfg(feature = "battery_lihv")]
+pub const DEFAULT_CHEMISTRY: Chemistry = Chemistry::LiHv;
+
+pub struct BatteryProfile {
+    /// Resting voltage at each of `POINTS` through the discharge.
+    voltages: [I16F16; 10],
+    /// Thresholds for each low-voltage protection stage, see `lvp::Stage`.
+    pub warn_volts: Voltage,
+    pub stepdown_volts: [Voltage; 2],
+    pub moon_volts: Voltage,
+    /// Below this the output is cut entirely.
+    pub cutoff_volts: Voltage,
+    /// Resting voltage of a freshly charged cell.
+    pub full_volts: Voltage,
+}
+
+static LI_ION: BatteryProfile = BatteryProfile {
+    voltages: [
+        I16F16!(3.0),
+        I16F16!(3.25),
+        I16F16!(3.45),
+        I16F16!(3.57),
+        I16F16!(3.68),
+        I16F16!(3.77),
+        I16F16!(3.85),
+        I16F16!(3.97),
+        I16F16!(4.05),
+        I16F16!(4.15),
+    ],
+    warn_volts: Voltage(I16F16!(3.4)),
+    stepdown_volts: [Voltage(I16F16!(3.3)), Voltage(I16F16!(3.2))],
+    moon_volts: Voltage(I16F16!(3.1)),
+    cutoff_volts: Voltage(I16F16!(3.0)),
+    full_volts: Voltage(I16F16!(4.2)),
+};
+
+// LiFePO4 spends most of its discharge on a plateau around 3.2-3.3v
+static LI_FE_PO4: BatteryProfile = BatteryProfile {
+    voltages: [
+        I16F16!(2.8),
+        I16F16!(3.0),
+        I16F16!(3.1),
+        I16F16!(3.18),
+        I16F16!(3.22),
+        I16F16!(3.25),
+        I16F16!(3.27),
+        I16F16!(3.3),
+        I16F16!(3.33),
+        I16F16!(3.4),
+    ],
+    warn_volts: Voltage(I16F16!(3.0)),
+    stepdown_volts: [Voltage(I16F16!(2.95)), Voltage(I16F16!(2.9))],
+    moon_volts: Voltage(I16F16!(2.8)),
+    cutoff_volts: Voltage(I16F16!(2.6)),
+    full_volts: Voltage(I16F16!(3.6)),
+};
+
+// high voltage li-ion, charged to 4.35v
+static LI_HV: BatteryProfile = BatteryProfile {
+    voltages: [
+        I16F16!(3.0),
+        I16F16!(3.3),
+        I16F16!(3.5),
+        I16F16!(3.62),
+        I16F16!(3.73),
+        I16F16!(3.83),
+        I16F16!(3.93),
+        I16F16!(4.05),
+        I16F16!(4.15),
+        I16F16!(4.3),
+    ],
+    warn_volts: Voltage(I16F16!(3.45)),
+    stepdown_volts: [Voltage(I16F16!(3.35)), Voltage(I16F16!(3.25))],
+    moon_volts: Voltage(I16F16!(3.1)),
+    cutoff_volts: Voltage(I16F16!(3.0)),
+    full_volts: Voltage(I16F16!(4.35)),
+};
+
+/// The profile for the battery chemistry picked in the settings.
+pub async fn profile() -> &'static BatteryProfile {
+    crate::settings::get().await.battery.profile()
+}
 
-    let i = (voltage - v_low) / (v_high - v_low);
-    lvl_low + i * (lvl_high - lvl_low)
+impl BatteryProfile {
+    pub fn voltage_to_level(&self, voltage: Voltage) -> I16F16 {
+        let voltage = voltage.0;
+        let idx = match self.voltages.binary_search(&voltage) {
+            Ok(idx) => return POINTS[idx],
+            Err(idx) => idx,
+        };
+
+        if idx == 0 {
+            return I16F16!(0.0);
+        }
+
+        if idx >= POINTS.len() {
+            return I16F16!(1.0);
+        }
+
+        let (v_low, v_high) = (self.voltages[idx - 1], self.voltages[idx]);
+        let (lvl_low, lvl_high) = (POINTS[idx - 1], POINTS[idx]);
+
+        let i = (voltage - v_low) / (v_high - v_low);
+        lvl_low + i * (lvl_high - lvl_low)
+    }
 }
@@ -0,0 +1,457 @@
+// STM32 implementations of the hardware traits in `tyrfing_stm::hal`
+
+use cichlid::ColorRGB;
+use defmt::debug;
+use embassy_stm32::{
+    adc::{self, Adc, SampleTime},
+    bind_interrupts,
+    dac::{Dac, DacCh1, DacCh2, Value},
+    dma::NoDma,
+    exti::ExtiInput,
+    gpio::{Flex, Output, OutputType, Pull},
+    peripherals::{ADC1, DAC1, EXTI8, IWDG, PA5, PA6, PA7, PB0, TIM3},
+    time::Hertz,
+    timer::{
+        low_level::CountingMode,
+        simple_pwm::{PwmPin, SimplePwm},
+        Channel,
+    },
+    wdg::IndependentWatchdog,
+    Peripheral, PeripheralRef,
+};
+use fixed::types::I16F16;
+use fixed_macro::types::I16F16;
+use tyrfing_stm::{
+    aux::Rgb1Bit,
+    hal,
+    monitoring::{Temp, Voltage},
+};
+
+use crate::pins;
+
+pub struct PowerDriver {
+    hdr: PeripheralRef<'static, pins::hdr!()>,
+    opamp_en: PeripheralRef<'static, pins::opamp_en!()>,
+    boost_en: PeripheralRef<'static, pins::boost_en!()>,
+    shunt_select: PeripheralRef<'static, pins::shunt_select!()>,
+    dac: PeripheralRef<'static, DAC1>,
+    dac_out: PeripheralRef<'static, pins::dac!()>,
+    pa5: PeripheralRef<'static, PA5>,
+}
+
+impl PowerDriver {
+    pub fn new(
+        hdr: pins::hdr!(),
+        opamp_en: pins::opamp_en!(),
+        boost_en: pins::boost_en!(),
+        shunt_select: pins::shunt_select!(),
+        dac: DAC1,
+        dac_out: pins::dac!(),
+        pa5: PA5,
+    ) -> Self {
+        Self {
+            hdr: hdr.into_ref(),
+            opamp_en: opamp_en.into_ref(),
+            boost_en: boost_en.into_ref(),
+            shunt_select: shunt_select.into_ref(),
+            dac: dac.into_ref(),
+            dac_out: dac_out.into_ref(),
+            pa5: pa5.into_ref(),
+        }
+    }
+}
+
+impl hal::PowerDriver for PowerDriver {
+    type Paths<'a> = PowerPaths<'a>;
+
+    fn bring_up(&mut self) -> PowerPaths<'_> {
+        let (mut dac_ch1, mut dac_ch2) = Dac::new(
+            self.dac.reborrow(),
+            NoDma,
+            NoDma,
+            self.dac_out.reborrow(),
+            self.pa5.reborrow(),
+        )
+        .split();
+        dac_ch2.set(Value::Bit8(0));
+        dac_ch1.set(Value::Bit8(0));
+        dac_ch2.set_enable(false);
+        dac_ch1.set_enable(false);
+        dac_ch1.set_output_buffer(false);
+
+        PowerPaths {
+            dac: dac_ch1,
+            _dac_ch2: dac_ch2,
+            hdr: Output::new(
+                self.hdr.reborrow(),
+                embassy_stm32::gpio::Level::Low,
+                embassy_stm32::gpio::Speed::Low,
+            ),
+            opamp_en: Output::new(
+                self.opamp_en.reborrow(),
+                embassy_stm32::gpio::Level::Low,
+                embassy_stm32::gpio::Speed::Low,
+            ),
+            boost_en: Output::new(
+                self.boost_en.reborrow(),
+                embassy_stm32::gpio::Level::Low,
+                embassy_stm32::gpio::Speed::Low,
+            ),
+            shunt_select: Output::new(
+                self.shunt_select.reborrow(),
+                embassy_stm32::gpio::Level::Low,
+                embassy_stm32::gpio::Speed::Low,
+            ),
+        }
+    }
+}
+
+pub struct PowerPaths<'a> {
+    dac: DacCh1<'a, DAC1>,
+    _dac_ch2: DacCh2<'a, DAC1>,
+    hdr: Output<'a>,
+    opamp_en: Output<'a>,
+    boost_en: Output<'a>,
+    shunt_select: Output<'a>,
+}
+
+impl<'a> PowerPaths<'a> {
+    fn set_hdr(&mut self, high_range: bool) {
+        self.hdr.set_level(high_range.into());
+        self.shunt_select.set_level(high_range.into());
+    }
+}
+
+impl<'a> hal::PowerPaths for PowerPaths<'a> {
+    async fn set(&mut self, level: u8) {
+        if level == 0 {
+            debug!("Setting light level to {}", level);
+            self.dac.set(Value::Bit8(0));
+            self.dac.disable();
+            self.set_hdr(false);
+            self.opamp_en.set_low();
+            self.boost_en.set_low();
+            tyrfing_stm::monitoring::poke_measuring();
+        } else {
+            if self.boost_en.is_set_low() {
+                self.dac.set(Value::Bit8(0));
+                self.dac.enable();
+                self.set_hdr(false);
+                self.opamp_en.set_high();
+                maitake::time::sleep(core::time::Duration::from_millis(40)).await;
+                self.boost_en.set_high();
+                tyrfing_stm::monitoring::poke_measuring();
+                debug!("Bringing up light");
+            }
+
+            let current_range = self.hdr.is_set_high();
+            let (high_range, dac) = tyrfing_stm::power_curve::setting_for(level, current_range);
+            debug!("hdr: {}, dac: {}", high_range, dac);
+
+            // a high range DAC code in the low range is a dim blip, but a low
+            // range code in the high range is a bright flash. so when moving
+            // up the DAC goes first, and when moving down the range does
+            if high_range && !current_range {
+                self.dac.set(Value::Bit12Right(dac));
+                self.set_hdr(true);
+            } else if !high_range && current_range {
+                self.set_hdr(false);
+                self.dac.set(Value::Bit12Right(dac));
+            } else {
+                self.dac.set(Value::Bit12Right(dac));
+            }
+        }
+    }
+}
+
+pub struct AuxLeds {
+    timer: PeripheralRef<'static, TIM3>,
+    r: PeripheralRef<'static, PA6>,
+    g: PeripheralRef<'static, PA7>,
+    b: PeripheralRef<'static, PB0>,
+}
+
+impl AuxLeds {
+    pub fn new(timer: TIM3, r: PA6, g: PA7, b: PB0) -> Self {
+        Self {
+            timer: timer.into_ref(),
+            r: r.into_ref(),
+            g: g.into_ref(),
+            b: b.into_ref(),
+        }
+    }
+}
+
+impl hal::AuxLeds for AuxLeds {
+    type Pwm<'a> = AuxPwm<'a>;
+    type Low<'a> = AuxLow<'a>;
+
+    fn pwm(&mut self) -> AuxPwm<'_> {
+        let pwm = SimplePwm::new(
+            self.timer.reborrow(),
+            Some(PwmPin::new_ch1(self.r.reborrow(), OutputType::PushPull)),
+            Some(PwmPin::new_ch2(self.g.reborrow(), OutputType::PushPull)),
+            Some(PwmPin::new_ch3(self.b.reborrow(), OutputType::PushPull)),
+            None,
+            Hertz::khz(5),
+            CountingMode::EdgeAlignedUp,
+        );
+
+        AuxPwm { pwm }
+    }
+
+    fn low(&mut self) -> AuxLow<'_> {
+        AuxLow {
+            r: Flex::new(self.r.reborrow()),
+            g: Flex::new(self.g.reborrow()),
+            b: Flex::new(self.b.reborrow()),
+        }
+    }
+}
+
+pub struct AuxPwm<'a> {
+    pwm: SimplePwm<'a, TIM3>,
+}
+
+impl<'a> hal::AuxPwm for AuxPwm<'a> {
+    fn set(&mut self, c: ColorRGB) {
+        let max_duty = self.pwm.get_max_duty();
+
+        let calc_duty = |v: u8| {
+            let d = (v as u32 * max_duty) / 255;
+
+            debug!("colour: {}, max_duty: {}, duty: {}", v, max_duty, d);
+
+            d
+        };
+
+        for (c, v) in [
+            (Channel::Ch1, c.r),
+            (Channel::Ch2, c.g),
+            (Channel::Ch3, c.b),
+        ] {
+            if v != 0 {
+                self.pwm.enable(c);
+            } else {
+                self.pwm.disable(c);
+            }
+            self.pwm.set_duty(c, calc_duty(v));
+        }
+    }
+}
+
+pub struct AuxLow<'a> {
+    r: Flex<'a>,
+    g: Flex<'a>,
+    b: Flex<'a>,
+}
+
+impl<'a> hal::AuxLow for AuxLow<'a> {
+    fn set(&mut self, c: Rgb1Bit) {
+        self.r.set_as_input(if c.r { Pull::Up } else { Pull::Down });
+        self.g.set_as_input(if c.g { Pull::Up } else { Pull::Down });
+        self.b.set_as_input(if c.b { Pull::Up } else { Pull::Down });
+    }
+}
+
+pub struct Button {
+    input: ExtiInput<'static>,
+}
+
+impl Button {
+    pub fn new(t: pins::button!(), ch: EXTI8) -> Self {
+        Self {
+            input: ExtiInput::new(t, ch, Pull::Up),
+        }
+    }
+}
+
+impl hal::Button for Button {
+    async fn wait_for_press(&mut self) {
+        self.input.wait_for_low().await;
+    }
+
+    fn is_pressed(&mut self) -> bool {
+        self.input.is_low()
+    }
+}
+
+pub struct ButtonLed {
+    led: Output<'static>,
+}
+
+impl ButtonLed {
+    pub fn new(led: pins::button_led!()) -> Self {
+        Self {
+            led: Output::new(
+                led,
+                embassy_stm32::gpio::Level::High,
+                embassy_stm32::gpio::Speed::Low,
+            ),
+        }
+    }
+}
+
+impl hal::ButtonLed for ButtonLed {
+    fn set(&mut self, on: bool) {
+        // the button LED is wired active low
+        self.led.set_level((!on).into());
+    }
+}
+
+bind_interrupts!(struct Irqs {
+    ADC1_COMP => adc::InterruptHandler<ADC1>;
+});
+
+const VREF_CAL: *const u16 = 0x1FF8_0078 as _;
+const TS_CAL1: *const u16 = 0x1FF8_007A as _;
+const TS_CAL2: *const u16 = 0x1FF8_007E as _;
+
+// the battery will measure 2.7v on the ADC when at the peak of 4.2v
+const BATTERY_VOLTAGE_FACTOR: I16F16 = I16F16!(4.2).unwrapped_div(I16F16!(2.7));
+
+struct Factors {
+    vref_scale: I16F16,
+    volts_scale: I16F16,
+    ts_cal_30: I16F16,
+    ts_cal_130: I16F16,
+    /// Correction from the in-field calibration, in degrees.
+    temp_offset: I16F16,
+}
+
+impl Factors {
+    async fn calculate(p: PeripheralRef<'_, ADC1>) -> Self {
+        let mut adc = Adc::new(p, Irqs);
+        adc.set_sample_time(SampleTime::CYCLES160_5);
+
+        let mut vrefint = adc.enable_vref();
+        let vrefint_sample = adc.read(&mut vrefint).await;
+
+        let vrefint_sample = I16F16::from_num(vrefint_sample);
+        let vref_cal = I16F16::from_num(unsafe { core::ptr::read_volatile(VREF_CAL) });
+
+        let vref_scale = vref_cal / vrefint_sample;
+        let batt_volts_scale = BATTERY_VOLTAGE_FACTOR * vref_scale * I16F16!(3.0) / I16F16!(4095);
+
+        let ts_cal_30 = I16F16::from_num(unsafe { core::ptr::read_volatile(TS_CAL1) });
+        let ts_cal_130 = I16F16::from_num(unsafe { core::ptr::read_volatile(TS_CAL2) });
+
+        Self {
+            vref_scale,
+            volts_scale: batt_volts_scale,
+            ts_cal_30,
+            ts_cal_130,
+            temp_offset: I16F16!(0.0),
+        }
+    }
+
+    fn volts_from_raw(&self, raw: u16) -> Voltage {
+        let v = I16F16::from_num(raw) * self.volts_scale;
+        Voltage(v)
+    }
+
+    fn temp_from_raw(&self, raw: u16) -> Temp {
+        let t = I16F16::from_num(raw) * self.vref_scale;
+        let t = t - self.ts_cal_30;
+        let t = t * I16F16!(100.0);
+        let t = t / (self.ts_cal_130 - self.ts_cal_30);
+        let t = t + I16F16!(30.0);
+        let t = t + self.temp_offset;
+
+        Temp(t)
+    }
+}
+
+pub struct Sensors {
+    bat_level: pins::battery_sense!(),
+    adc: PeripheralRef<'static, ADC1>,
+    factors: Option<Factors>,
+}
+
+impl Sensors {
+    pub fn new(bat_level: pins::battery_sense!(), adc: ADC1) -> Self {
+        Self {
+            bat_level,
+            adc: adc.into_ref(),
+            factors: None,
+        }
+    }
+}
+
+impl hal::Sensors for Sensors {
+    type Session<'a> = SensorSession<'a>;
+
+    async fn session(&mut self) -> SensorSession<'_> {
+        if self.factors.is_none() {
+            self.factors = Some(Factors::calculate(self.adc.reborrow()).await);
+        }
+
+        // the calibration can change at any time, so pick it up every session
+        let temp_offset = tyrfing_stm::settings::get().await.temp_offset;
+        if let Some(factors) = self.factors.as_mut() {
+            factors.temp_offset = I16F16::from_num(temp_offset);
+        }
+
+        let mut adc = Adc::new(self.adc.reborrow(), Irqs);
+        adc.set_sample_time(SampleTime::CYCLES160_5);
+
+        let tempsense = adc.enable_temperature();
+
+        SensorSession {
+            adc,
+            tempsense,
+            bat_level: &mut self.bat_level,
+            factors: self.factors.as_ref().unwrap(),
+        }
+    }
+}
+
+pub struct Supervisor {
+    watchdog: IndependentWatchdog<'static, IWDG>,
+}
+
+impl Supervisor {
+    pub fn new(wd: IWDG) -> Self {
+        let mut watchdog = IndependentWatchdog::new(wd, 6_000_000);
+        watchdog.unleash();
+
+        Self { watchdog }
+    }
+}
+
+impl hal::Supervisor for Supervisor {
+    fn pet_watchdog(&mut self) {
+        self.watchdog.pet();
+    }
+
+    fn emergency_stop(&mut self) -> ! {
+        let en_pin = unsafe { embassy_stm32::peripherals::PA3::steal() };
+        let mut en_pin = Output::new(
+            en_pin,
+            embassy_stm32::gpio::Level::Low,
+            embassy_stm32::gpio::Speed::Low,
+        );
+        en_pin.set_low();
+
+        cortex_m::peripheral::SCB::sys_reset();
+    }
+}
+
+pub struct SensorSession<'a> {
+    adc: Adc<'a, ADC1>,
+    tempsense: adc::Temperature,
+    bat_level: &'a mut pins::battery_sense!(),
+    factors: &'a Factors,
+}
+
+impl<'a> hal::SensorSession for SensorSession<'a> {
+    async fn voltage(&mut self) -> Voltage {
+        let v = self.adc.read(self.bat_level).await;
+        self.factors.volts_from_raw(v)
+    }
+
+    async fn temp(&mut self) -> Temp {
+        let t = self.adc.read(&mut self.tempsense).await;
+        self.factors.temp_from_raw(t)
+    }
+}
@@ -0,0 +1,180 @@
+use embassy_futures::select::{select, Either};
+use maitake::time::{Duration, Instant};
+
+use crate::{
+    click::{ButtonState, Listener},
+    hal::ButtonLed,
+    settings::Settings,
+};
+
+// The button LED is on a plain GPIO (PC15 has no timer channel), so dimming
+// it takes software PWM: each frame it's on for part of the frame and off for
+// the rest. That keeps the MCU awake, so frames only run while the pattern is
+// lit below full brightness. Dark stretches and full brightness are slept
+// through in one go.
+
+const FRAME_MS: u32 = 4;
+/// Brightness settings go from 0 (off) to this, in ms on per frame.
+pub const MAX_BRIGHTNESS: u8 = FRAME_MS as u8;
+
+// longest to go without checking whether there's something else to show
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Pattern {
+    Off,
+    Steady,
+    /// Slowly fading in and out.
+    Breathe,
+    /// A short blip every few seconds, for finding the light in the dark.
+    Locator,
+    /// Two quick blinks every couple of seconds, shown over everything else
+    /// once the battery is getting low.
+    LowBattery,
+}
+
+impl Pattern {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Pattern::Off => 0,
+            Pattern::Steady => 1,
+            Pattern::Breathe => 2,
+            Pattern::Locator => 3,
+            Pattern::LowBattery => 4,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Pattern::Off),
+            1 => Some(Pattern::Steady),
+            2 => Some(Pattern::Breathe),
+            3 => Some(Pattern::Locator),
+            4 => Some(Pattern::LowBattery),
+            _ => None,
+        }
+    }
+
+    /// How bright the pattern is `ms` into it, out of 255.
+    fn intensity(self, ms: u32) -> u8 {
+        match self {
+            Pattern::Off => 0,
+            Pattern::Steady => 255,
+            Pattern::Breathe => {
+                let t = ms % 4000;
+                let ramp = (if t < 2000 { t } else { 4000 - t }) * 255 / 2000;
+                // squared so it spends longer near dim, where it looks like
+                // it's changing the most
+                (ramp * ramp / 255) as u8
+            }
+            Pattern::Locator => {
+                if ms % 3000 < 100 {
+                    255
+                } else {
+                    0
+                }
+            }
+            Pattern::LowBattery => match ms % 2000 {
+                0..=99 | 200..=299 => 255,
+                _ => 0,
+            },
+        }
+    }
+
+    /// How much longer the intensity stays what it is `ms` into the pattern,
+    /// zero if it never holds still.
+    fn holds_for(self, ms: u32) -> u32 {
+        match self {
+            Pattern::Off | Pattern::Steady => u32::MAX,
+            Pattern::Breathe => 0,
+            Pattern::Locator => {
+                let t = ms % 3000;
+                if t < 100 {
+                    100 - t
+                } else {
+                    3000 - t
+                }
+            }
+            Pattern::LowBattery => {
+                let t = ms % 2000;
+                [100, 200, 300, 2000].into_iter().find(|&e| e > t).unwrap() - t
+            }
+        }
+    }
+}
+
+async fn current_pattern(settings: &Settings) -> Pattern {
+    let profile = crate::battery_level::profile().await;
+    let volts = *crate::monitoring::RESTING_VOLTAGE.lock().await;
+
+    if volts < profile.warn_volts {
+        Pattern::LowBattery
+    } else if crate::state::is_unlocked().await {
+        settings.button_led_unlocked
+    } else {
+        settings.button_led_locked
+    }
+}
+
+// keeps track of whether the button is down, the LED is always lit while it is
+struct Pressed {
+    states: Listener<ButtonState>,
+    pressed: bool,
+}
+
+impl Pressed {
+    fn catch_up(&mut self) {
+        while let Some(s) = self.states.try_next() {
+            self.pressed = s.value == ButtonState::Press;
+        }
+    }
+
+    async fn wait(&mut self, duration: Duration) {
+        if let Either::Second(s) = select(maitake::time::sleep(duration), self.states.next()).await
+        {
+            self.pressed = s.value == ButtonState::Press;
+        }
+    }
+}
+
+// #[embassy_executor::task]
+pub async fn button_led_task(mut led: impl ButtonLed) {
+    let mut button = Pressed {
+        states: crate::click::subscribe_states(),
+        pressed: false,
+    };
+    let start = Instant::now();
+
+    loop {
+        button.catch_up();
+
+        let settings = crate::settings::get().await;
+        let (pattern, brightness) = if button.pressed {
+            (Pattern::Steady, MAX_BRIGHTNESS)
+        } else {
+            (
+                current_pattern(&settings).await,
+                settings.button_led_brightness.min(MAX_BRIGHTNESS),
+            )
+        };
+
+        let ms = start.elapsed().as_millis() as u32;
+        let on_ms = (pattern.intensity(ms) as u32 * brightness as u32 + 127) / 255;
+
+        if on_ms == 0 || on_ms >= FRAME_MS {
+            // nothing to dim, hold the LED where it is until the pattern
+            // changes or the button does. Breathing changes all the time, it
+            // gets looked at again a frame later
+            led.set(on_ms > 0);
+
+            let hold = Duration::from_millis(pattern.holds_for(ms).max(FRAME_MS) as u64);
+            button.wait(hold.min(IDLE_POLL)).await;
+            continue;
+        }
+
+        led.set(true);
+        maitake::time::sleep(Duration::from_millis(on_ms as u64)).await;
+        led.set(false);
+        maitake::time::sleep(Duration::from_millis((FRAME_MS - on_ms) as u64)).await;
+    }
+}
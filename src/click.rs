@@ -1,116 +1,298 @@
-use defmt::info;
-use embassy_stm32::{
-    exti::ExtiInput,
-    gpio::Output,
-    peripherals::{EXTI8, PA8, PC15},
+use core::{cell::Cell, future::Future};
+
+use defmt::{info, warn};
+use embassy_sync::{
+    blocking_mutex::{self, raw::ThreadModeRawMutex},
+    mutex::Mutex,
+    pubsub::{PubSubChannel, Subscriber, WaitResult},
 };
+use maitake::time::{Duration, Instant};
+
+use crate::{hal::Button, settings::Settings};
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum ButtonEvent {
-    Click1,
-    Click2,
-    Click3,
-    Click4,
-    Click5,
-    Click6,
-    Click7,
-
-    Hold1,
-    Hold2,
-    Hold3,
-    Hold4,
-    Hold5,
-    Hold6,
-    Hold7,
-
-    HoldEnd,
+    /// Some number of clicks.
+    Click(u8),
+    /// Some number of clicks, with the button held down on the last one.
+    Hold(u8),
+    /// The button was let go after a hold.
+    HoldEnd { clicks: u8, duration: Duration },
 }
 
 impl ButtonEvent {
-    pub fn click_from_count(n: u8) -> Self {
-        match n {
-            1 => Self::Click1,
-            2 => Self::Click2,
-            3 => Self::Click3,
-            4 => Self::Click4,
-            5 => Self::Click5,
-            6 => Self::Click6,
-            _ => Self::Click7,
+    /// The number of clicks for a click event.
+    pub fn click_count(self) -> Option<u8> {
+        match self {
+            Self::Click(n) => Some(n),
+            _ => None,
         }
     }
+}
 
-    pub fn hold_from_count(n: u8) -> Self {
-        match n {
-            1 => Self::Hold1,
-            2 => Self::Hold2,
-            3 => Self::Hold3,
-            4 => Self::Hold4,
-            5 => Self::Hold5,
-            6 => Self::Hold6,
-            _ => Self::Hold7,
+impl defmt::Format for ButtonEvent {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            Self::Click(n) => defmt::write!(f, "Click({})", n),
+            Self::Hold(n) => defmt::write!(f, "Hold({})", n),
+            Self::HoldEnd { clicks, duration } => {
+                defmt::write!(f, "HoldEnd({}, {}ms)", clicks, duration.as_millis() as u32)
+            }
         }
     }
 }
 
-pub static BUTTON_EVENTS: embassy_sync::signal::Signal<
-    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
-    ButtonEvent,
-> = embassy_sync::signal::Signal::new();
+/// Something that came from the button, and when it happened.
+#[derive(Clone, Copy)]
+pub struct Timestamped<T> {
+    pub at: Instant,
+    pub value: T,
+}
+
+// how far each subscriber may fall behind, deep enough to cover the UI being
+// busy with a blink or a mode change for a while. Anything past this is
+// dropped and counted
+const QUEUE_LEN: usize = 8;
+// event generator, ui, aux, button led, logger and one spare
+const MAX_SUBSCRIBERS: usize = 6;
+
+type Bus<T> = PubSubChannel<ThreadModeRawMutex, Timestamped<T>, QUEUE_LEN, MAX_SUBSCRIBERS, 1>;
+
+/// Decoded clicks and holds.
+static BUTTON_EVENTS: Bus<ButtonEvent> = PubSubChannel::new();
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
 pub enum ButtonState {
     Depress,
     Press,
 }
 
-static BUTTON_STATES: embassy_sync::signal::Signal<
-    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
-    ButtonState,
-> = embassy_sync::signal::Signal::new();
+/// Debounced presses and releases, the event generator is one subscriber.
+static BUTTON_STATES: Bus<ButtonState> = PubSubChannel::new();
 
-pub static LOCKOUT_BUTTON_STATES: embassy_sync::signal::Signal<
-    embassy_sync::blocking_mutex::raw::ThreadModeRawMutex,
-    ButtonState,
-> = embassy_sync::signal::Signal::new();
+// states and events missed by subscribers falling too far behind, summed over
+// all of them, only for the log
+static DROPPED: blocking_mutex::Mutex<ThreadModeRawMutex, Cell<u32>> =
+    blocking_mutex::Mutex::new(Cell::new(0));
+
+fn publish<T: Clone>(bus: &'static Bus<T>, at: Instant, value: T) {
+    bus.immediate_publisher()
+        .publish_immediate(Timestamped { at, value });
+}
+
+/// One subscriber's view of a button bus. Only what's published after
+/// subscribing is seen.
+pub struct Listener<T: Clone + 'static> {
+    sub: Subscriber<'static, ThreadModeRawMutex, Timestamped<T>, QUEUE_LEN, MAX_SUBSCRIBERS, 1>,
+}
+
+impl<T: Clone> Listener<T> {
+    fn new(bus: &'static Bus<T>) -> Self {
+        Self {
+            sub: bus.subscriber().expect("too many button subscribers"),
+        }
+    }
+
+    fn lagged(n: u64) {
+        let dropped = DROPPED.lock(|d| {
+            d.set(d.get().wrapping_add(n as u32));
+            d.get()
+        });
+        warn!("Button subscriber fell behind, {} dropped so far", dropped);
+    }
+
+    /// Wait for the next message, in the order they were published.
+    pub async fn next(&mut self) -> Timestamped<T> {
+        loop {
+            match self.sub.next_message().await {
+                WaitResult::Message(m) => return m,
+                WaitResult::Lagged(n) => Self::lagged(n),
+            }
+        }
+    }
+
+    /// The next message if one is waiting.
+    pub fn try_next(&mut self) -> Option<Timestamped<T>> {
+        loop {
+            match self.sub.try_next_message()? {
+                WaitResult::Message(m) => return Some(m),
+                WaitResult::Lagged(n) => Self::lagged(n),
+            }
+        }
+    }
+}
+
+pub fn subscribe_events() -> Listener<ButtonEvent> {
+    Listener::new(&BUTTON_EVENTS)
+}
+
+pub fn subscribe_states() -> Listener<ButtonState> {
+    Listener::new(&BUTTON_STATES)
+}
+
+// the ui's own subscription, shared by everything waiting on `next_event`
+static UI_EVENTS: Mutex<ThreadModeRawMutex, Option<Listener<ButtonEvent>>> = Mutex::new(None);
+
+/// Wait for the next button event for the UI, in the order they happened.
+pub async fn next_event() -> ButtonEvent {
+    next_timestamped_event().await.value
+}
+
+/// Like `next_event`, but with when the event happened.
+pub async fn next_timestamped_event() -> Timestamped<ButtonEvent> {
+    UI_EVENTS
+        .lock()
+        .await
+        .get_or_insert_with(subscribe_events)
+        .next()
+        .await
+}
+
+/// How presses and releases get turned into clicks and holds.
+#[derive(Clone, Copy)]
+pub struct Timing {
+    /// How long the button has to stay put before a change counts.
+    pub debounce: Duration,
+    /// How long after a release another press still adds to the clicks.
+    pub click_window: Duration,
+    /// How long the button has to be held down for a hold.
+    pub hold: Duration,
+}
+
+impl Timing {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            // polling for the release with no debounce would never yield
+            debounce: Duration::from_millis(settings.debounce_ms.max(1) as u64),
+            click_window: Duration::from_millis(settings.click_window_ms as u64),
+            hold: Duration::from_millis(settings.hold_ms as u64),
+        }
+    }
+}
 
 #[derive(Clone, Copy)]
 pub enum EventGenState {
     FirstClick,
     ForHigh { clicks: u8 },
-    ForLow { clicks: u8 },
-    HoldFinish,
+    ForLow { clicks: u8, pressed: Instant },
+    HoldFinish { clicks: u8, pressed: Instant },
 }
 
-// #[embassy_executor::task]
-pub async fn debouncer_task(t: PA8, ch: EXTI8, led: PC15) {
-    let mut t = ExtiInput::new(t, ch, embassy_stm32::gpio::Pull::Up);
-    let mut led = Output::new(
-        led,
-        embassy_stm32::gpio::Level::High,
-        embassy_stm32::gpio::Speed::Low,
-    );
+/// The click and hold state machine, without any of the waiting so that it
+/// can be driven by anything that can tell it what the button did.
+pub struct EventGen {
+    state: EventGenState,
+}
 
-    loop {
-        led.set_high();
+impl EventGen {
+    pub const fn new() -> Self {
+        Self {
+            state: EventGenState::FirstClick,
+        }
+    }
+
+    /// How long after the last change to wait for the next one before giving
+    /// up on it, `None` to wait forever.
+    pub fn window(&self, timing: &Timing) -> Option<Duration> {
+        match self.state {
+            EventGenState::FirstClick | EventGenState::HoldFinish { .. } => None,
+            EventGenState::ForHigh { .. } => Some(timing.click_window),
+            EventGenState::ForLow { .. } => Some(timing.hold),
+        }
+    }
+
+    /// Feed in the next button state, or `None` if the window ran out before
+    /// one came in, `at` being when either happened. Returns the event this
+    /// completes, if any.
+    pub fn feed(&mut self, next: Option<ButtonState>, at: Instant) -> Option<ButtonEvent> {
+        let expecting = match self.state {
+            EventGenState::FirstClick | EventGenState::ForHigh { .. } => ButtonState::Press,
+            EventGenState::ForLow { .. } | EventGenState::HoldFinish { .. } => ButtonState::Depress,
+        };
+
+        // r: true if the button changed as expected, false if the window ran out
+        let r = match next {
+            Some(s) if s == expecting => true,
+            Some(_) => {
+                self.state = EventGenState::FirstClick;
+                return None;
+            }
+            None => false,
+        };
+
+        let (state, evt) = match self.state {
+            EventGenState::FirstClick => (
+                EventGenState::ForLow {
+                    clicks: 1,
+                    pressed: at,
+                },
+                None,
+            ),
+            EventGenState::ForHigh { clicks } => {
+                if r {
+                    (
+                        EventGenState::ForLow {
+                            clicks: clicks.saturating_add(1),
+                            pressed: at,
+                        },
+                        None,
+                    )
+                } else {
+                    (EventGenState::FirstClick, Some(ButtonEvent::Click(clicks)))
+                }
+            }
+            EventGenState::ForLow { clicks, pressed } => {
+                if r {
+                    (EventGenState::ForHigh { clicks }, None)
+                } else {
+                    (
+                        EventGenState::HoldFinish { clicks, pressed },
+                        Some(ButtonEvent::Hold(clicks)),
+                    )
+                }
+            }
+            EventGenState::HoldFinish { clicks, pressed } => (
+                EventGenState::FirstClick,
+                Some(ButtonEvent::HoldEnd {
+                    clicks,
+                    duration: at.checked_duration_since(pressed).unwrap_or(Duration::ZERO),
+                }),
+            ),
+        };
+        self.state = state;
+
+        evt
+    }
+}
 
-        info!("Button pin: {}", t.is_high());
+impl Default for EventGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        t.wait_for_low().await;
-        let v = t.is_low();
+// #[embassy_executor::task]
+pub async fn debouncer_task(mut t: impl Button) {
+    loop {
+        info!("Button pin: {}", !t.is_pressed());
+
+        t.wait_for_press().await;
+        let pressed_at = Instant::now();
+        let v = t.is_pressed();
 
         // if the button isn't pressed, abort
         if !v {
             continue;
         }
 
-        led.set_low();
+        let debounce = Timing::from_settings(&crate::settings::get().await).debounce;
 
-        maitake::time::sleep(core::time::Duration::from_millis(16)).await;
+        maitake::time::sleep(debounce).await;
 
-        // if the button is still pressed after 16ms, consider it debounced and pressed
-        if t.is_low() {
-            BUTTON_STATES.signal(ButtonState::Press);
-            LOCKOUT_BUTTON_STATES.signal(ButtonState::Press);
+        // if the button is still pressed after the debounce time, consider it
+        // debounced and pressed
+        if t.is_pressed() {
+            publish(&BUTTON_STATES, pressed_at, ButtonState::Press);
         } else {
             continue;
         }
@@ -118,86 +300,240 @@ pub async fn debouncer_task(t: PA8, ch: EXTI8, led: PC15) {
         // once pressed, we poll the button for depresses since sometimes the
         // edge interrupt can be missed
         loop {
-            maitake::time::sleep(core::time::Duration::from_millis(16)).await;
+            maitake::time::sleep(debounce).await;
             // if the button is still pressed, do nothing
-            if t.is_low() {
+            if t.is_pressed() {
                 continue;
             }
+            let released_at = Instant::now();
 
-            maitake::time::sleep(core::time::Duration::from_millis(16)).await;
+            maitake::time::sleep(debounce).await;
 
             // if the button has been depressed for two cycles, consider it
             // debounced and depressed
-            if t.is_high() {
-                BUTTON_STATES.signal(ButtonState::Depress);
-                LOCKOUT_BUTTON_STATES.signal(ButtonState::Depress);
+            if !t.is_pressed() {
+                publish(&BUTTON_STATES, released_at, ButtonState::Depress);
                 break;
             }
         }
     }
 }
 
-// #[embassy_executor::task]
-pub async fn event_generator_task() {
-    let mut state = EventGenState::FirstClick;
+// where the event generator gets debounced button states from
+trait StateSource {
+    /// The next state, or `None` if `deadline` passes before one comes in. With
+    /// no deadline `None` means nothing more is coming.
+    async fn next_before(&mut self, deadline: Option<Instant>) -> Option<Timestamped<ButtonState>>;
+}
+
+impl StateSource for Listener<ButtonState> {
+    async fn next_before(&mut self, deadline: Option<Instant>) -> Option<Timestamped<ButtonState>> {
+        match deadline {
+            Some(d) => match d.checked_duration_since(Instant::now()) {
+                Some(left) => maitake::time::timeout(left, self.next()).await.ok(),
+                None => self.try_next(),
+            },
+            None => Some(self.next().await),
+        }
+    }
+}
+
+// turns states into clicks and holds, handing each to `emit` as the window it
+// completes closes. Returns once `states` runs dry
+async fn generate_events<F: Future<Output = Timing>>(
+    states: &mut impl StateSource,
+    mut timing: impl FnMut() -> F,
+    mut emit: impl FnMut(Timestamped<ButtonEvent>),
+) {
+    let mut gen = EventGen::new();
+    // the windows run from when the button last changed, not from when that
+    // change was taken off the queue
+    let mut last_change = Instant::now();
+    // a state that came in after the window closed, it belongs to whatever
+    // comes next
+    let mut pending: Option<Timestamped<ButtonState>> = None;
+
     loop {
-        let (wait_until, expecting) = match state {
-            EventGenState::FirstClick => (None, ButtonState::Press),
-            EventGenState::ForHigh { .. } => (
-                Some(core::time::Duration::from_millis(300)),
-                ButtonState::Press,
-            ),
-            EventGenState::ForLow { .. } => (
-                Some(core::time::Duration::from_millis(300)),
-                ButtonState::Depress,
-            ),
-            EventGenState::HoldFinish => (None, ButtonState::Depress),
-        };
+        let deadline = gen.window(&timing().await).map(|w| last_change + w);
 
-        let r = if let Some(timeout) = wait_until {
-            maitake::time::timeout(timeout, BUTTON_STATES.wait()).await
-        } else {
-            Ok(BUTTON_STATES.wait().await)
+        let next = match pending.take() {
+            Some(s) => Some(s),
+            None => states.next_before(deadline).await,
         };
 
-        let r = match r {
-            Ok(state) if state == expecting => true,
-            Ok(_) => {
-                state = EventGenState::FirstClick;
-                continue;
+        let next = match (next, deadline) {
+            (Some(s), Some(d)) if s.at > d => {
+                pending = Some(s);
+                None
             }
-            Err(_) => false,
+            (next, _) => next,
         };
 
-        // r: true if pressed, false if held
+        // clicks and holds happen when the window closes, hold ends when the
+        // button comes back up
+        let at = match (next, deadline) {
+            (Some(s), _) => s.at,
+            (None, Some(d)) => d,
+            (None, None) => return,
+        };
+        if next.is_some() {
+            last_change = at;
+        }
 
-        let (state_, evt) = match state {
-            EventGenState::FirstClick => (EventGenState::ForLow { clicks: 1 }, None),
-            EventGenState::ForHigh { clicks } => {
-                if r {
-                    (EventGenState::ForLow { clicks: clicks + 1 }, None)
-                } else {
-                    (
-                        EventGenState::FirstClick,
-                        Some(ButtonEvent::click_from_count(clicks)),
-                    )
-                }
-            }
-            EventGenState::ForLow { clicks } => {
-                if r {
-                    (EventGenState::ForHigh { clicks }, None)
-                } else {
-                    (
-                        EventGenState::HoldFinish,
-                        Some(ButtonEvent::hold_from_count(clicks)),
-                    )
-                }
-            }
-            EventGenState::HoldFinish => (EventGenState::FirstClick, Some(ButtonEvent::HoldEnd)),
+        if let Some(value) = gen.feed(next.map(|s| s.value), at) {
+            emit(Timestamped { at, value });
+        }
+    }
+}
+
+// #[embassy_executor::task]
+pub async fn event_generator_task() {
+    let mut states = subscribe_states();
+
+    generate_events(
+        &mut states,
+        || async { Timing::from_settings(&crate::settings::get().await) },
+        |e| publish(&BUTTON_EVENTS, e.at, e.value),
+    )
+    .await;
+}
+
+// #[embassy_executor::task]
+pub async fn button_logger_task() {
+    let mut states = subscribe_states();
+    let mut events = subscribe_events();
+
+    loop {
+        match embassy_futures::select::select(states.next(), events.next()).await {
+            embassy_futures::select::Either::First(s) => info!("Button {}", s.value),
+            embassy_futures::select::Either::Second(e) => info!("Button event {}", e.value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ButtonState::{Depress as Up, Press as Down};
+
+    const TIMING: Timing = Timing {
+        debounce: Duration::from_millis(16),
+        click_window: Duration::from_millis(300),
+        hold: Duration::from_millis(300),
+    };
+
+    // hands over every edge straight away, even ones after the window has
+    // closed, which the generator has to hold back itself
+    struct Script<I> {
+        start: Instant,
+        edges: I,
+    }
+
+    impl<I: Iterator<Item = (u64, ButtonState)>> StateSource for Script<I> {
+        async fn next_before(&mut self, _: Option<Instant>) -> Option<Timestamped<ButtonState>> {
+            let (ms, value) = self.edges.next()?;
+            Some(Timestamped {
+                at: self.start + Duration::from_millis(ms),
+                value,
+            })
+        }
+    }
+
+    // `edges` are ms from the start, returns events with the ms they happened
+    // at
+    fn run(timing: &Timing, edges: &[(u64, ButtonState)]) -> Vec<(u64, ButtonEvent)> {
+        let start = crate::test_support::now();
+        let mut script = Script {
+            start,
+            edges: edges.iter().copied(),
         };
-        state = state_;
-        if let Some(evt) = evt {
-            BUTTON_EVENTS.signal(evt);
+        let mut events = Vec::new();
+
+        embassy_futures::block_on(generate_events(
+            &mut script,
+            || async { *timing },
+            |e| events.push((e.at.duration_since(start).as_millis() as u64, e.value)),
+        ));
+
+        events
+    }
+
+    fn held(clicks: u8, ms: u64) -> ButtonEvent {
+        ButtonEvent::HoldEnd {
+            clicks,
+            duration: Duration::from_millis(ms),
         }
     }
+
+    #[test]
+    fn single_click() {
+        let events = run(&TIMING, &[(0, Down), (100, Up)]);
+
+        // the click only counts once no second press comes in
+        assert!(events == [(400, ButtonEvent::Click(1))]);
+    }
+
+    #[test]
+    fn double_click() {
+        let events = run(&TIMING, &[(0, Down), (100, Up), (250, Down), (350, Up)]);
+
+        assert!(events == [(650, ButtonEvent::Click(2))]);
+    }
+
+    #[test]
+    fn many_clicks() {
+        let edges: Vec<_> = (0..12u64)
+            .flat_map(|i| [(i * 150, Down), (i * 150 + 60, Up)])
+            .collect();
+        let events = run(&TIMING, &edges);
+
+        assert!(events == [(11 * 150 + 60 + 300, ButtonEvent::Click(12))]);
+    }
+
+    #[test]
+    fn hold_then_release() {
+        let events = run(&TIMING, &[(0, Down), (1000, Up)]);
+
+        assert!(events == [(300, ButtonEvent::Hold(1)), (1000, held(1, 1000))]);
+    }
+
+    #[test]
+    fn click_then_hold() {
+        let events = run(&TIMING, &[(0, Down), (100, Up), (200, Down), (900, Up)]);
+
+        // the hold duration runs from the press that became the hold
+        assert!(events == [(500, ButtonEvent::Hold(2)), (900, held(2, 700))]);
+    }
+
+    #[test]
+    fn slow_clicks_are_separate() {
+        let events = run(&TIMING, &[(0, Down), (100, Up), (450, Down), (550, Up)]);
+
+        assert!(events == [(400, ButtonEvent::Click(1)), (850, ButtonEvent::Click(1))]);
+    }
+
+    #[test]
+    fn windows_follow_the_timing() {
+        let slow = Timing {
+            click_window: Duration::from_millis(500),
+            hold: Duration::from_millis(600),
+            ..TIMING
+        };
+
+        // a gap that splits clicks by default is one double click here
+        let events = run(&slow, &[(0, Down), (100, Up), (450, Down), (550, Up)]);
+        assert!(events == [(1050, ButtonEvent::Click(2))]);
+
+        // and a press that is a hold by default is still a click
+        let events = run(&slow, &[(0, Down), (500, Up)]);
+        assert!(events == [(1000, ButtonEvent::Click(1))]);
+    }
+
+    #[test]
+    fn out_of_order_state_resets() {
+        // a release with nothing pressed, e.g. the button was down at boot
+        let events = run(&TIMING, &[(0, Up), (100, Down), (200, Up)]);
+
+        assert!(events == [(500, ButtonEvent::Click(1))]);
+    }
 }
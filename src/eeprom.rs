@@ -0,0 +1,94 @@
+use embassy_stm32::pac;
+
+use tyrfing_stm::settings::{Eeprom, EepromError};
+
+// data EEPROM of the STM32L072
+const EEPROM_BASE: usize = 0x0808_0000;
+const EEPROM_SIZE: usize = 6 * 1024;
+
+pub struct DataEeprom {
+    _private: (),
+}
+
+impl DataEeprom {
+    pub fn new() -> Self {
+        Self { _private: () }
+    }
+
+    fn unlock(&mut self) {
+        if pac::FLASH.pecr().read().pelock() {
+            pac::FLASH.pekeyr().write_value(0x89AB_CDEF);
+            pac::FLASH.pekeyr().write_value(0x0203_0405);
+        }
+    }
+
+    fn lock(&mut self) {
+        pac::FLASH.pecr().modify(|w| w.set_pelock(true));
+    }
+
+    fn wait_ready(&mut self) -> Result<(), EepromError> {
+        while pac::FLASH.sr().read().bsy() {}
+
+        let sr = pac::FLASH.sr().read();
+        let failed = sr.wrperr() || sr.pgaerr() || sr.sizerr() || sr.notzeroerr();
+
+        // the error flags are cleared by writing ones to them
+        pac::FLASH.sr().write(|w| {
+            w.set_eop(true);
+            w.set_wrperr(true);
+            w.set_pgaerr(true);
+            w.set_sizerr(true);
+            w.set_notzeroerr(true);
+        });
+
+        if failed {
+            Err(EepromError::Write)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn write_unlocked(&mut self, offset: usize, data: &[u8]) -> Result<(), EepromError> {
+        let mut addr = EEPROM_BASE + offset;
+        let mut data = data;
+
+        while !data.is_empty() {
+            // the data EEPROM can be written a byte at a time, but writing
+            // whole words where we can saves a lot of time
+            if addr % 4 == 0 && data.len() >= 4 {
+                let word = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                unsafe { core::ptr::write_volatile(addr as *mut u32, word) };
+                addr += 4;
+                data = &data[4..];
+            } else {
+                unsafe { core::ptr::write_volatile(addr as *mut u8, data[0]) };
+                addr += 1;
+                data = &data[1..];
+            }
+
+            self.wait_ready()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Eeprom for DataEeprom {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe { core::ptr::read_volatile((EEPROM_BASE + offset + i) as *const u8) };
+        }
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EepromError> {
+        if offset + data.len() > EEPROM_SIZE {
+            return Err(EepromError::OutOfRange);
+        }
+
+        self.unlock();
+        let r = self.write_unlocked(offset, data);
+        self.lock();
+
+        r
+    }
+}
@@ -0,0 +1,115 @@
+use defmt::info;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use fixed::types::I16F16;
+use fixed_macro::types::I16F16;
+use maitake::time::Duration;
+
+use crate::{battery_level::BatteryProfile, monitoring::Voltage};
+
+// Coulomb counting from the commanded level. The power going into the LEDs for
+// each level comes out of the power curve, dividing that by the battery voltage
+// and boost efficiency gives the current drawn from the battery.
+
+// rough efficiency of the boost converter
+const BOOST_EFFICIENCY_PERCENT: u64 = 90;
+
+// a resting voltage this close to full, and this far above the one the light
+// last turned on with, means a freshly charged cell went in
+const FRESH_MARGIN: I16F16 = I16F16!(0.05);
+
+struct Counter {
+    used_uah: u32,
+    // charge used that hasn't made up a whole µAh yet, in mA·ms
+    residue: u32,
+    // resting voltage at the last turn on, in mV
+    resting_mv: u16,
+}
+
+static COUNTER: Mutex<ThreadModeRawMutex, Counter> = Mutex::new(Counter {
+    used_uah: 0,
+    residue: 0,
+    resting_mv: 0,
+});
+
+fn millivolts(volts: Voltage) -> u16 {
+    (volts.0 * I16F16!(1000)).saturating_to_num::<u16>()
+}
+
+/// Estimated current drawn from the battery at `level`, in mA.
+pub fn battery_current_ma(level: u8, volts: Voltage) -> u32 {
+    if level == 0 {
+        return 0;
+    }
+
+    let power_mw = crate::power_curve::power_level(level).power_mw as u64;
+    let millivolts = millivolts(volts).max(1) as u64;
+
+    (power_mw * 1000 * 100 / (millivolts * BOOST_EFFICIENCY_PERCENT)) as u32
+}
+
+/// Pick up the charge used so far from the settings.
+pub async fn init() {
+    let settings = crate::settings::get().await;
+    let mut counter = COUNTER.lock().await;
+    counter.used_uah = settings.used_mah as u32 * 1000;
+    counter.resting_mv = settings.resting_mv;
+}
+
+/// Start counting from zero if the resting voltage says the battery was just
+/// charged or swapped: it's near full and has risen since the light was last
+/// turned on.
+pub async fn check_for_fresh_battery(volts: Voltage, profile: &BatteryProfile) {
+    let mut counter = COUNTER.lock().await;
+    let last_mv = core::mem::replace(&mut counter.resting_mv, millivolts(volts));
+    let last_volts = I16F16::saturating_from_num(last_mv) / I16F16!(1000);
+
+    let near_full = volts.0 + FRESH_MARGIN >= profile.full_volts.0;
+    let risen = volts.0 >= last_volts + FRESH_MARGIN;
+    if !(near_full && risen) {
+        return;
+    }
+
+    if counter.used_uah != 0 {
+        info!("Fresh battery, resetting used charge");
+        counter.used_uah = 0;
+        counter.residue = 0;
+    }
+}
+
+/// Account for running at `level` for `dt`.
+pub async fn account(level: u8, volts: Voltage, dt: Duration) {
+    let mams = battery_current_ma(level, volts) * dt.as_millis() as u32;
+
+    let mut counter = COUNTER.lock().await;
+    counter.residue += mams;
+    counter.used_uah = counter.used_uah.saturating_add(counter.residue / 3600);
+    counter.residue %= 3600;
+}
+
+/// Charge used since the last fresh battery, in mAh.
+pub async fn used_mah() -> u16 {
+    (COUNTER.lock().await.used_uah / 1000).min(u16::MAX as u32) as u16
+}
+
+/// Store the charge used so far and the last resting voltage, this happens
+/// when the light turns off rather than continuously to go easy on the EEPROM.
+pub async fn persist() {
+    let used_mah = used_mah().await;
+    let resting_mv = COUNTER.lock().await.resting_mv;
+    crate::settings::update(|s| {
+        s.used_mah = used_mah;
+        s.resting_mv = resting_mv;
+    })
+    .await;
+}
+
+/// Estimated time left running at `level`, in minutes.
+pub async fn remaining_minutes(level: u8) -> u32 {
+    let capacity_mah = crate::settings::get().await.capacity_mah as u32;
+    let remaining_mah = capacity_mah.saturating_sub(used_mah().await as u32);
+
+    let volts = *crate::monitoring::VOLTAGE.lock().await;
+    let current_ma = battery_current_ma(level, volts).max(1);
+
+    remaining_mah * 60 / current_ma
+}
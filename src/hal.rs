@@ -0,0 +1,79 @@
+// The points where the firmware touches hardware. The firmware binary
+// implements these on top of the STM32 peripherals, the simulator implements
+// them against a virtual flashlight.
+
+use cichlid::ColorRGB;
+
+use crate::{
+    aux::Rgb1Bit,
+    monitoring::{Temp, Voltage},
+};
+
+pub trait PowerDriver {
+    type Paths<'a>: PowerPaths
+    where
+        Self: 'a;
+
+    /// Claim the power paths, they start out with the light off and are
+    /// released again when dropped.
+    fn bring_up(&mut self) -> Self::Paths<'_>;
+}
+
+pub trait PowerPaths {
+    /// Drive the emitter at `level`, where zero is off and 1..=255 index
+    /// into the power curve.
+    async fn set(&mut self, level: u8);
+}
+
+pub trait AuxLeds {
+    type Pwm<'a>: AuxPwm
+    where
+        Self: 'a;
+    type Low<'a>: AuxLow
+    where
+        Self: 'a;
+
+    fn pwm(&mut self) -> Self::Pwm<'_>;
+    fn low(&mut self) -> Self::Low<'_>;
+}
+
+pub trait AuxPwm {
+    fn set(&mut self, c: ColorRGB);
+}
+
+/// Aux LEDs driven through the pull up/down resistors, for when the light is
+/// locked and we want to draw as little as possible.
+pub trait AuxLow {
+    fn set(&mut self, c: Rgb1Bit);
+}
+
+pub trait Button {
+    async fn wait_for_press(&mut self);
+    fn is_pressed(&mut self) -> bool;
+}
+
+/// The LED behind the button, any brightness control happens above this.
+pub trait ButtonLed {
+    fn set(&mut self, on: bool);
+}
+
+pub trait Sensors {
+    type Session<'a>: SensorSession
+    where
+        Self: 'a;
+
+    /// Power up the ADC, it is powered down again when the session is dropped.
+    async fn session(&mut self) -> Self::Session<'_>;
+}
+
+pub trait SensorSession {
+    async fn voltage(&mut self) -> Voltage;
+    async fn temp(&mut self) -> Temp;
+}
+
+pub trait Supervisor {
+    fn pet_watchdog(&mut self);
+
+    /// Cut power to the emitter and reset.
+    fn emergency_stop(&mut self) -> !;
+}
@@ -0,0 +1,25 @@
+#![feature(type_alias_impl_trait)]
+#![feature(impl_trait_in_assoc_type)]
+#![feature(impl_trait_in_fn_trait_return)]
+#![feature(async_closure)]
+#![allow(async_fn_in_trait)]
+#![cfg_attr(not(test), no_std)]
+
+pub mod aux;
+pub mod battery_level;
+pub mod button_led;
+pub mod click;
+pub mod energy;
+pub mod hal;
+pub mod lvp;
+pub mod monitoring;
+pub mod power;
+pub mod power_curve;
+pub mod ramp;
+pub mod settings;
+pub mod state;
+#[cfg(test)]
+mod test_support;
+pub mod thermal;
+pub mod turbo;
+pub mod ui;
@@ -0,0 +1,123 @@
+use maitake::time::Duration;
+
+use crate::{battery_level::BatteryProfile, monitoring::Voltage};
+
+// how long the voltage has to stay under a threshold before the next stage
+// kicks in, so that a dip from a load step doesn't count
+const CONFIRM_TIME: Duration = Duration::from_secs(2);
+
+/// Stages of low-voltage protection, in the order the battery goes through
+/// them.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, defmt::Format)]
+pub enum Stage {
+    Normal,
+    Warning,
+    Stepdown1,
+    Stepdown2,
+    /// Just enough light to get home with.
+    Moon,
+    Shutoff,
+}
+
+impl Stage {
+    fn next(self) -> Option<Stage> {
+        match self {
+            Stage::Normal => Some(Stage::Warning),
+            Stage::Warning => Some(Stage::Stepdown1),
+            Stage::Stepdown1 => Some(Stage::Stepdown2),
+            Stage::Stepdown2 => Some(Stage::Moon),
+            Stage::Moon => Some(Stage::Shutoff),
+            Stage::Shutoff => None,
+        }
+    }
+
+    /// The resting voltage below which the battery enters this stage.
+    fn threshold(self, profile: &BatteryProfile) -> Voltage {
+        match self {
+            // never comes after anything
+            Stage::Normal => profile.full_volts,
+            Stage::Warning => profile.warn_volts,
+            Stage::Stepdown1 => profile.stepdown_volts[0],
+            Stage::Stepdown2 => profile.stepdown_volts[1],
+            Stage::Moon => profile.moon_volts,
+            Stage::Shutoff => profile.cutoff_volts,
+        }
+    }
+
+    /// Highest level the light may run at in this stage.
+    pub fn level_cap(self) -> u8 {
+        match self {
+            Stage::Normal | Stage::Warning => 255,
+            Stage::Stepdown1 => 100,
+            Stage::Stepdown2 => 50,
+            Stage::Moon => 10,
+            Stage::Shutoff => 0,
+        }
+    }
+}
+
+/// Walks through the low-voltage stages as the resting voltage drops. Stages
+/// only ever advance, a battery that recovers a little after stepping down
+/// stays stepped down until the light is turned off. Dropping below the
+/// cutoff skips straight to shutting off.
+pub struct LowVoltageProtection {
+    stage: Stage,
+    below_for: Duration,
+}
+
+impl LowVoltageProtection {
+    /// Start out at the deepest stage `volts` is already below, so that a
+    /// battery that was run down last time doesn't get to start at full
+    /// output again.
+    pub fn starting_at(volts: Voltage, profile: &BatteryProfile) -> Self {
+        let mut stage = Stage::Normal;
+        while let Some(next) = stage.next() {
+            if volts >= next.threshold(profile) {
+                break;
+            }
+            stage = next;
+        }
+
+        Self {
+            stage,
+            below_for: Duration::ZERO,
+        }
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// Feed in the resting voltage after `dt`, returns the new stage when it
+    /// changes.
+    pub fn update(
+        &mut self,
+        volts: Voltage,
+        profile: &BatteryProfile,
+        dt: Duration,
+    ) -> Option<Stage> {
+        let next = self.stage.next()?;
+
+        // no waiting around once the cell is below its cutoff
+        if volts < profile.cutoff_volts {
+            self.below_for = Duration::ZERO;
+            self.stage = Stage::Shutoff;
+            return Some(Stage::Shutoff);
+        }
+
+        if volts >= next.threshold(profile) {
+            self.below_for = Duration::ZERO;
+            return None;
+        }
+
+        self.below_for += dt;
+        if self.below_for < CONFIRM_TIME {
+            return None;
+        }
+
+        self.below_for = Duration::ZERO;
+        self.stage = next;
+
+        Some(next)
+    }
+}
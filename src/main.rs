@@ -15,15 +15,11 @@ use panic_reset as _;
 #[cfg(feature = "debug")]
 use {defmt_rtt as _, panic_probe as _};
 
-mod aux;
-mod battery_level;
-mod click;
-mod monitoring;
+use tyrfing_stm::{aux, button_led, click, monitoring, power, settings, ui};
+
+mod board;
+mod eeprom;
 mod pins;
-mod power;
-mod power_curve;
-mod state;
-mod ui;
 
 #[cfg(feature = "use_maitake_executor")]
 mod executor;
@@ -99,12 +95,14 @@ fn main() -> ! {
     static RTC: StaticCell<Rtc> = StaticCell::new();
     let rtc = RTC.init(rtc);
 
+    let mut settings_store = settings::Store::new(eeprom::DataEeprom::new());
+    settings::init(settings_store.load());
+
     spawn!(monitoring::monitoring_task(
-        pins::take_battery_sense!(p),
-        p.ADC1,
-        p.IWDG
+        board::Sensors::new(pins::take_battery_sense!(p), p.ADC1),
+        board::Supervisor::new(p.IWDG)
     ));
-    spawn!(power::power_task(
+    spawn!(power::power_task(board::PowerDriver::new(
         pins::take_hdr!(p),
         pins::take_opamp_en!(p),
         pins::take_boost_en!(p),
@@ -112,19 +110,23 @@ fn main() -> ! {
         p.DAC1,
         pins::take_dac!(p),
         p.PA5
-    ));
-    spawn!(aux::aux_task(
+    )));
+    spawn!(aux::aux_task(board::AuxLeds::new(
         p.TIM3,
         pins::take_aux_r!(p),
         pins::take_aux_g!(p),
         pins::take_aux_b!(p)
-    ));
-    spawn!(click::debouncer_task(
+    )));
+    spawn!(click::debouncer_task(board::Button::new(
         pins::take_button!(p),
-        p.EXTI8,
+        p.EXTI8
+    )));
+    spawn!(button_led::button_led_task(board::ButtonLed::new(
         pins::take_button_led!(p)
-    ));
+    )));
     spawn!(click::event_generator_task());
+    spawn!(click::button_logger_task());
+    spawn!(settings::settings_task(settings_store));
     spawn!(ui::torch_ui_task());
 
     crate::executor::run(rtc);
@@ -1,13 +1,11 @@
-use defmt::info;
-use embassy_stm32::adc::{Adc, SampleTime};
-use embassy_stm32::peripherals::{ADC1, IWDG, PA0};
-use embassy_stm32::wdg::IndependentWatchdog;
-use embassy_stm32::{adc, bind_interrupts, Peripheral, PeripheralRef};
+use defmt::{debug, info};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
 
 use fixed::types::I16F16;
 use fixed_macro::types::I16F16;
 
+use crate::hal::{SensorSession, Sensors, Supervisor};
+
 static POKE_MEASURING: embassy_sync::signal::Signal<ThreadModeRawMutex, ()> =
     embassy_sync::signal::Signal::new();
 
@@ -23,145 +21,154 @@ pub struct Voltage(pub I16F16);
 
 pub static TEMP: Mutex<ThreadModeRawMutex, Temp> = Mutex::new(Temp(I16F16!(20)));
 pub static VOLTAGE: Mutex<ThreadModeRawMutex, Voltage> = Mutex::new(Voltage(I16F16!(4.2)));
+/// [`VOLTAGE`] with the sag from the current load added back on, this is what
+/// the battery would read at rest.
+pub static RESTING_VOLTAGE: Mutex<ThreadModeRawMutex, Voltage> = Mutex::new(Voltage(I16F16!(4.2)));
+/// Whether the voltage readings have settled since reset. Until then
+/// [`VOLTAGE`] and [`RESTING_VOLTAGE`] are placeholders.
+pub static VOLTAGE_SETTLED: Mutex<ThreadModeRawMutex, bool> = Mutex::new(false);
+/// Rate of change of [`TEMP`], in degrees per second.
+pub static TEMP_SLOPE: Mutex<ThreadModeRawMutex, I16F16> = Mutex::new(I16F16!(0));
 
-bind_interrupts!(struct Irqs {
-    ADC1_COMP => adc::InterruptHandler<ADC1>;
-});
-
-const VREF_CAL: *const u16 = 0x1FF8_0078 as _;
-const TS_CAL1: *const u16 = 0x1FF8_007A as _;
-const TS_CAL2: *const u16 = 0x1FF8_007E as _;
+struct Smoother(I16F16);
 
-// the battery will measure 2.7v on the ADC when at the peak of 4.2v
-const BATTERY_VOLTAGE_FACTOR: I16F16 = I16F16!(4.2).unwrapped_div(I16F16!(2.7));
+impl Smoother {
+    pub fn update(&mut self, value: I16F16) {
+        let diff = (value / I16F16!(8)) - (self.0 / I16F16!(8));
 
-struct Factors {
-    vref_scale: I16F16,
-    volts_scale: I16F16,
-    ts_cal_30: I16F16,
-    ts_cal_130: I16F16,
+        self.0 = self.0.saturating_add(diff);
+    }
 }
 
-impl Factors {
-    async fn calculate<'a>(p: PeripheralRef<'a, ADC1>) -> Self {
-        let mut adc = Adc::new(p, Irqs);
-        adc.set_sample_time(SampleTime::CYCLES160_5);
-
-        let mut vrefint = adc.enable_vref();
-        let vrefint_sample = adc.read(&mut vrefint).await;
-
-        let vrefint_sample = I16F16::from_num(vrefint_sample);
-        let vref_cal = I16F16::from_num(unsafe { core::ptr::read_volatile(VREF_CAL) });
-
-        let vref_scale = vref_cal / vrefint_sample;
-        let batt_volts_scale = BATTERY_VOLTAGE_FACTOR * vref_scale * I16F16!(3.0) / I16F16!(4095);
-
-        let ts_cal_30 = I16F16::from_num(unsafe { core::ptr::read_volatile(TS_CAL1) });
-        let ts_cal_130 = I16F16::from_num(unsafe { core::ptr::read_volatile(TS_CAL2) });
+const DEFAULT_RESISTANCE: I16F16 = I16F16!(0.08);
+const MIN_RESISTANCE: I16F16 = I16F16!(0.01);
+const MAX_RESISTANCE: I16F16 = I16F16!(0.5);
+// load steps smaller than this are too noisy to learn from
+const MIN_CURRENT_STEP: I16F16 = I16F16!(0.3);
+
+/// Estimates the internal resistance from how the voltage moves when the load
+/// steps up or down, such as when the boost comes up or shuts off.
+struct ResistanceEstimator {
+    last: Option<(I16F16, I16F16)>,
+    ohms: I16F16,
+}
 
-        Self {
-            vref_scale,
-            volts_scale: batt_volts_scale,
-            ts_cal_30,
-            ts_cal_130,
+impl ResistanceEstimator {
+    fn update(&mut self, volts: I16F16, amps: I16F16) {
+        if let Some((last_volts, last_amps)) = self.last {
+            let d_amps = amps - last_amps;
+
+            if d_amps.abs() >= MIN_CURRENT_STEP {
+                let sample = ((last_volts - volts) / d_amps).clamp(MIN_RESISTANCE, MAX_RESISTANCE);
+                self.ohms += (sample - self.ohms) / I16F16!(4);
+
+                debug!(
+                    "Load step of {}A, resistance sample: {}, estimate: {}",
+                    defmt::Display2Format(&d_amps),
+                    defmt::Display2Format(&sample),
+                    defmt::Display2Format(&self.ohms)
+                );
+            }
         }
-    }
-
-    fn volts_from_raw(&self, raw: u16) -> Voltage {
-        let v = I16F16::from_num(raw) * self.volts_scale;
-        Voltage(v)
-    }
-
-    fn temp_from_raw(&self, raw: u16) -> Temp {
-        let t = I16F16::from_num(raw) * self.vref_scale;
-        let t = t - self.ts_cal_30;
-        let t = t * I16F16!(100.0);
-        let t = t / (self.ts_cal_130 - self.ts_cal_30);
-        let t = t + I16F16!(30.0);
 
-        Temp(t)
+        self.last = Some((volts, amps));
     }
 }
 
-struct Smoother(I16F16);
-
-impl Smoother {
-    pub fn update(&mut self, value: I16F16) {
-        let diff = (value / I16F16!(8)) - (self.0 / I16F16!(8));
-
-        self.0 = self.0.saturating_add(diff);
-    }
-}
+// voltage samples taken before the readings count as settled
+const SETTLE_SAMPLES: u8 = 3;
 
 struct Smoothers {
     temp: TemperatureSmoother,
     voltage: Smoother,
+    resistance: ResistanceEstimator,
+    voltage_samples: u8,
 }
 
 // #[embassy_executor::task]
-pub async fn monitoring_task(mut bat_level: PA0, adc: ADC1, wd: IWDG) {
-    let mut adc = adc.into_ref();
-
-    let mut watchdog = IndependentWatchdog::new(wd, 6_000_000);
-    watchdog.unleash();
-
+pub async fn monitoring_task(mut sensors: impl Sensors, mut supervisor: impl Supervisor) {
     let mut smoothers = Smoothers {
         temp: TemperatureSmoother::new(I16F16!(0.0), I16F16!(1.0), I16F16!(4.0)),
         voltage: Smoother(I16F16!(4.2)),
+        resistance: ResistanceEstimator {
+            last: None,
+            ohms: DEFAULT_RESISTANCE,
+        },
+        voltage_samples: 0,
     };
 
-    let factors = Factors::calculate(adc.reborrow()).await;
-
     loop {
         if crate::state::is_on().await {
-            measure_while_on(
-                &mut watchdog,
-                &mut bat_level,
-                adc.reborrow(),
-                &factors,
-                &mut smoothers,
-            )
-            .await;
+            measure_while_on(&mut sensors, &mut supervisor, &mut smoothers).await;
         } else {
-            measure_while_off(
-                &mut watchdog,
-                &mut bat_level,
-                adc.reborrow(),
-                &factors,
-                &mut smoothers,
-            )
-            .await;
+            measure_while_off(&mut sensors, &mut supervisor, &mut smoothers).await;
         }
     }
 }
 
 async fn measure_and_update(
-    watchdog: &mut IndependentWatchdog<'_, IWDG>,
-    bat_level: &mut PA0,
-    tempsense: &mut adc::Temperature,
-    adc: &mut Adc<'_, ADC1>,
-    factors: &Factors,
+    session: &mut impl SensorSession,
+    supervisor: &mut impl Supervisor,
     smoothers: &mut Smoothers,
     timestep: I16F16,
 ) {
-    let v = adc.read(bat_level).await;
-    let v = factors.volts_from_raw(v);
-    smoothers.voltage.update(v.0);
+    let v = session.voltage().await;
+    if smoothers.voltage_samples == 0 {
+        // start from a real reading rather than creeping in from the
+        // placeholder
+        smoothers.voltage.0 = v.0;
+    } else {
+        smoothers.voltage.update(v.0);
+    }
 
     *VOLTAGE.lock().await = Voltage(smoothers.voltage.0);
 
-    let t = adc.read(tempsense).await;
-    let t = factors.temp_from_raw(t);
+    let level = crate::power::output_level().await;
+    let amps = I16F16::from_num(crate::energy::battery_current_ma(level, v)) / I16F16!(1000);
+    smoothers.resistance.update(v.0, amps);
+
+    let ohms = smoothers.resistance.ohms;
+
+    let load_amps = I16F16::from_num(crate::energy::battery_current_ma(
+        level,
+        Voltage(smoothers.voltage.0),
+    )) / I16F16!(1000);
+    *RESTING_VOLTAGE.lock().await = Voltage(
+        smoothers
+            .voltage
+            .0
+            .saturating_add(load_amps.saturating_mul(ohms)),
+    );
+
+    if smoothers.voltage_samples < SETTLE_SAMPLES {
+        smoothers.voltage_samples += 1;
+
+        if smoothers.voltage_samples == SETTLE_SAMPLES {
+            *VOLTAGE_SETTLED.lock().await = true;
+
+            // a reset is usually a battery swap, now's the first chance to
+            // tell whether the new one is full
+            let resting_volts = *RESTING_VOLTAGE.lock().await;
+            crate::energy::check_for_fresh_battery(
+                resting_volts,
+                crate::battery_level::profile().await,
+            )
+            .await;
+        }
+    }
+
+    let t = session.temp().await;
     smoothers.temp.update(t.0);
     smoothers.temp.predict(timestep);
 
     *TEMP.lock().await = Temp(smoothers.temp.value());
+    *TEMP_SLOPE.lock().await = smoothers.temp.slope();
 
     if t.0 > I16F16!(60.0) {
-        crate::state::emergency_stop();
+        supervisor.emergency_stop();
     }
 
-    watchdog.pet();
+    supervisor.pet_watchdog();
 
     info!(
         "v: {}, t: {}",
@@ -171,28 +178,14 @@ async fn measure_and_update(
 }
 
 async fn measure_while_on(
-    watchdog: &mut IndependentWatchdog<'_, IWDG>,
-    bat_level: &mut PA0,
-    p: PeripheralRef<'_, ADC1>,
-    factors: &Factors,
+    sensors: &mut impl Sensors,
+    supervisor: &mut impl Supervisor,
     smoothers: &mut Smoothers,
 ) {
-    let mut adc = Adc::new(p, Irqs);
-    adc.set_sample_time(SampleTime::CYCLES160_5);
-
-    let mut tempsense = adc.enable_temperature();
+    let mut session = sensors.session().await;
 
     loop {
-        measure_and_update(
-            watchdog,
-            bat_level,
-            &mut tempsense,
-            &mut adc,
-            factors,
-            smoothers,
-            I16F16!(0.25),
-        )
-        .await;
+        measure_and_update(&mut session, supervisor, smoothers, I16F16!(0.25)).await;
 
         if !crate::state::is_on().await {
             return;
@@ -203,34 +196,20 @@ async fn measure_while_on(
 }
 
 async fn measure_while_off(
-    watchdog: &mut IndependentWatchdog<'_, IWDG>,
-    bat_level: &mut PA0,
-    mut p: PeripheralRef<'_, ADC1>,
-    factors: &Factors,
+    sensors: &mut impl Sensors,
+    supervisor: &mut impl Supervisor,
     smoothers: &mut Smoothers,
 ) {
     loop {
-        let mut adc = Adc::new(p.reborrow(), Irqs);
-        adc.set_sample_time(SampleTime::CYCLES160_5);
-
-        let mut tempsense = adc.enable_temperature();
-
-        measure_and_update(
-            watchdog,
-            bat_level,
-            &mut tempsense,
-            &mut adc,
-            factors,
-            smoothers,
-            I16F16!(4.0),
-        )
-        .await;
+        let mut session = sensors.session().await;
+
+        measure_and_update(&mut session, supervisor, smoothers, I16F16!(4.0)).await;
 
         if crate::state::is_on().await {
             return;
         }
 
-        drop(adc);
+        drop(session);
 
         let _ =
             maitake::time::timeout(core::time::Duration::from_secs(4), POKE_MEASURING.wait()).await;
@@ -301,4 +280,8 @@ impl TemperatureSmoother {
     fn value(&self) -> I16F16 {
         self.x.x
     }
+
+    fn slope(&self) -> I16F16 {
+        self.x.y
+    }
 }
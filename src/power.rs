@@ -1,19 +1,16 @@
-use defmt::{debug, info, trace};
-use embassy_stm32::{
-    dac::{Dac, DacCh1, Value},
-    dma::NoDma,
-    gpio::Output,
-    peripherals::{DAC1, PA5},
-    Peripheral,
-};
+use defmt::{info, trace};
+use embassy_sync::signal::Signal;
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
-use embassy_time::Duration;
-use fixed::types::U32F32;
-use fixed_macro::types::{I16F16, I32F32};
+use fixed::types::I16F16;
+use fixed_macro::types::I16F16;
+use maitake::time::{Duration, Instant};
 
 use crate::{
-    monitoring::{Temp, Voltage},
-    pins,
+    hal::{PowerDriver, PowerPaths},
+    lvp::{self, LowVoltageProtection},
+    monitoring::Temp,
+    ramp::Ramp,
+    thermal::{self, ThermalRegulator},
 };
 
 static DESIRED_LEVEL: embassy_sync::mutex::Mutex<ThreadModeRawMutex, u8> = Mutex::new(0);
@@ -38,6 +35,95 @@ pub async fn blink(blinks: u8) {
     }
 }
 
+const READOUT_LEVEL: u8 = 30;
+
+// the levels a readout pulses between
+#[derive(Clone, Copy)]
+struct Readout {
+    pulse: u8,
+    rest: u8,
+}
+
+impl Readout {
+    const FROM_OFF: Readout = Readout {
+        pulse: READOUT_LEVEL,
+        rest: 0,
+    };
+
+    // dips down from a level the light is already on at, or flashes up for
+    // levels too low to dip from visibly. Never going to zero keeps the power
+    // paths up
+    fn over(level: u8) -> Readout {
+        let pulse = if level >= 16 {
+            level / 4
+        } else {
+            READOUT_LEVEL
+        };
+
+        Readout { pulse, rest: level }
+    }
+
+    async fn digit(self, digit: u8) {
+        let (blinks, on_time) = if digit == 0 {
+            (1, Duration::from_millis(600))
+        } else {
+            (digit, Duration::from_millis(150))
+        };
+
+        for _ in 0..blinks {
+            set_level(self.pulse).await;
+            maitake::time::sleep(on_time).await;
+            set_level(self.rest).await;
+            maitake::time::sleep(Duration::from_millis(350)).await;
+        }
+    }
+
+    async fn digits(self, digits: &[u8]) {
+        for (i, digit) in digits.iter().enumerate() {
+            if i != 0 {
+                maitake::time::sleep(Duration::from_millis(1000)).await;
+            }
+
+            self.digit(*digit).await;
+        }
+    }
+
+    async fn number(self, n: u32) {
+        let mut digits = [0u8; 10];
+        let mut len = 0;
+        let mut n = n;
+
+        loop {
+            digits[len] = (n % 10) as u8;
+            len += 1;
+            n /= 10;
+
+            if n == 0 {
+                break;
+            }
+        }
+
+        digits[..len].reverse();
+        self.digits(&digits[..len]).await;
+    }
+}
+
+/// Blink out the decimal digits of `n` from off.
+pub async fn blink_number(n: u32) {
+    Readout::FROM_OFF.number(n).await;
+}
+
+/// Blink out the decimal digits of `n` while the light is on at `level`, as
+/// dips from it.
+pub async fn blink_number_over(level: u8, n: u32) {
+    Readout::over(level).number(n).await;
+}
+
+/// Blink out a sequence of digits from off, with a pause between each.
+pub async fn blink_digits(digits: &[u8]) {
+    Readout::FROM_OFF.digits(digits).await;
+}
+
 pub async fn set_level_gradual(level: u8) {
     let mut gradual_level = GRADUAL_LEVEL.lock().await;
 
@@ -57,53 +143,15 @@ pub async fn set_level(level: u8) {
     set_level_gradual(level).await;
 }
 
-struct PowerPaths<'a> {
-    dac: DacCh1<'a, DAC1>,
-    hdr: Output<'a>,
-    opamp_en: Output<'a>,
-    boost_en: Output<'a>,
-    shunt_select: Output<'a>,
-}
-
-impl<'a> PowerPaths<'a> {
-    fn set_hdr(&mut self, high_range: bool) {
-        self.hdr.set_level(high_range.into());
-        self.shunt_select.set_level(high_range.into());
-    }
-
-    async fn set(&mut self, level: u8) {
-        if level == 0 {
-            debug!("Setting light level to {}", level);
-            self.dac.set(embassy_stm32::dac::Value::Bit8(0));
-            self.dac.disable();
-            self.set_hdr(false);
-            self.opamp_en.set_low();
-            self.boost_en.set_low();
-        } else {
-            if self.boost_en.is_set_low() {
-                self.dac.set(embassy_stm32::dac::Value::Bit8(0));
-                self.dac.enable();
-                self.set_hdr(false);
-                self.opamp_en.set_high();
-                maitake::time::sleep(core::time::Duration::from_millis(40)).await;
-                self.boost_en.set_high();
-                crate::monitoring::poke_measuring();
-                debug!("Bringing up light");
-            }
+static OUTPUT_LEVEL: Mutex<ThreadModeRawMutex, u8> = Mutex::new(0);
 
-            let config = &crate::power_curve::POWER_LEVELS[(level - 1) as usize];
-            debug!("hdr: {}, dac: {}", config.hdr, config.dac);
-            self.dac
-                .set(embassy_stm32::dac::Value::Bit12Right(config.dac));
-            self.set_hdr(config.hdr.into());
-        }
-    }
+/// The level the emitter is actually running at, after thermal and voltage
+/// limits.
+pub async fn output_level() -> u8 {
+    *OUTPUT_LEVEL.lock().await
 }
 
 const INSTANT_STOP_TEMP: Temp = Temp(I16F16!(50.0));
-const MAX_TEMP: Temp = Temp(I16F16!(40.0));
-const MIN_VOLTS: Voltage = Voltage(I16F16!(3.0));
-const INSTANT_STOP_VOLTS: Voltage = Voltage(I16F16!(3.0));
 
 fn delta(desired_level: u8, gradual_level: u8) -> u8 {
     let abs_diff = desired_level.abs_diff(gradual_level);
@@ -117,11 +165,76 @@ fn delta(desired_level: u8, gradual_level: u8) -> u8 {
     }
 }
 
-async fn handle_on_state<'a>(mut paths: PowerPaths<'a>) {
+const TICK: Duration = Duration::from_millis(10);
+const TICK_SECS: I16F16 = I16F16!(0.01);
+
+/// Signalled when the light has to turn off and lock by itself.
+pub static FORCE_OFF: Signal<ThreadModeRawMutex, ()> = Signal::new();
+
+const FLASH_DIP_TICKS: u8 = 15;
+const FLASH_TICKS: u8 = 4 * FLASH_DIP_TICKS;
+
+// dips the output a couple of times to let the user know something happened,
+// counted off in ticks so the loop keeps regulating through it
+#[derive(Default)]
+struct WarningFlash {
+    ticks_left: u8,
+}
+
+impl WarningFlash {
+    fn start(&mut self) {
+        self.ticks_left = FLASH_TICKS;
+    }
+
+    fn apply(&mut self, level: u8) -> u8 {
+        if self.ticks_left == 0 {
+            return level;
+        }
+
+        let elapsed = FLASH_TICKS - self.ticks_left;
+        self.ticks_left -= 1;
+
+        if level != 0 && (elapsed / FLASH_DIP_TICKS) % 2 == 0 {
+            (level / 4).max(1)
+        } else {
+            level
+        }
+    }
+}
+
+async fn handle_on_state(mut paths: impl PowerPaths) {
     let mut previous_level = 0u8;
+    let mut flash = WarningFlash::default();
+    // set once the turbo budget runs out, until something asks for a level
+    // the light can sustain
+    let mut turbo_spent = false;
+
+    let settings = crate::settings::get().await;
+    let battery = settings.battery.profile();
+    // settings from older firmware may allow more than the menu does now
+    let max_temp = settings.max_temp.min(thermal::MAX_CEILING);
+    let mut regulator = ThermalRegulator::new(Temp(I16F16::from_num(max_temp)));
+    let mut lvp = LowVoltageProtection::starting_at(
+        *crate::monitoring::RESTING_VOLTAGE.lock().await,
+        battery,
+    );
+
+    if lvp.stage() != lvp::Stage::Normal {
+        info!("Starting at low voltage stage: {}", lvp.stage());
+
+        if lvp.stage() == lvp::Stage::Shutoff {
+            FORCE_OFF.signal(());
+        }
+    }
 
-    let mut accumulated_over_temp = U32F32::ZERO;
-    let mut accumulated_under_volts = U32F32::ZERO;
+    // until the readings have settled after a reset the voltage is only a
+    // placeholder, and it would look like a full battery
+    if *crate::monitoring::VOLTAGE_SETTLED.lock().await {
+        let resting_volts = *crate::monitoring::RESTING_VOLTAGE.lock().await;
+        crate::energy::check_for_fresh_battery(resting_volts, battery).await;
+    }
+
+    let mut last_tick = Instant::now();
 
     loop {
         let gradual_level = *GRADUAL_LEVEL.lock().await;
@@ -141,10 +254,9 @@ async fn handle_on_state<'a>(mut paths: PowerPaths<'a>) {
         let mut actual_level = desired_level;
 
         let volts = *crate::monitoring::VOLTAGE.lock().await;
-
-        if volts < INSTANT_STOP_VOLTS {
-            actual_level = 0;
-        }
+        // judge the battery by its resting voltage so that sag under heavy
+        // load doesn't trip things early
+        let resting_volts = *crate::monitoring::RESTING_VOLTAGE.lock().await;
 
         let temp = *crate::monitoring::TEMP.lock().await;
 
@@ -152,111 +264,108 @@ async fn handle_on_state<'a>(mut paths: PowerPaths<'a>) {
             actual_level = 0;
         }
 
-        let temp_diff = temp.0 - MAX_TEMP.0;
+        let temp_slope = *crate::monitoring::TEMP_SLOPE.lock().await;
+        let thermal_limit = regulator.update(temp, temp_slope, TICK_SECS);
 
-        accumulated_over_temp =
-            accumulated_over_temp.saturating_add_signed(temp_diff.saturating_to_num());
+        trace!("Thermal limit: {}", thermal_limit);
 
-        trace!(
-            "Accumulated over temp: {}",
-            defmt::Display2Format(&accumulated_over_temp)
-        );
+        actual_level = actual_level.min(thermal_limit);
 
-        accumulated_under_volts =
-            accumulated_under_volts.saturating_add_signed(if volts < MIN_VOLTS {
-                I32F32!(1.0)
-            } else {
-                I32F32!(-1.0)
-            });
+        if let Some(stage) = lvp.update(resting_volts, battery, TICK) {
+            info!(
+                "Low voltage stage: {}, at {}v",
+                stage,
+                defmt::Display2Format(&resting_volts.0)
+            );
 
-        trace!(
-            "Accumulated under volts: {}",
-            defmt::Display2Format(&accumulated_under_volts)
-        );
+            match stage {
+                lvp::Stage::Shutoff => FORCE_OFF.signal(()),
+                _ => flash.start(),
+            }
+        }
 
-        // TODO: model this and tune the values correctly
-        const TICKS_PER_SEC: u64 = 100;
-        let power_decrease = (accumulated_under_volts / U32F32::from_num(TICKS_PER_SEC))
-            .saturating_add(accumulated_over_temp / U32F32::from_num(TICKS_PER_SEC));
+        actual_level = actual_level.min(lvp.stage().level_cap());
 
-        actual_level = actual_level.saturating_sub(power_decrease.int().saturating_to_num());
+        // the ramp may be reconfigured while the light is on
+        let (turbo_from, sustained) = {
+            let settings = crate::settings::get().await;
+            let ramp = Ramp::from_settings(&settings);
+            (ramp.ceiling, ramp.clamp(settings.turbo_sustained_level))
+        };
+
+        // only turbo itself uses up the budget, anything between the
+        // sustained level and the ceiling is left to the thermal limit
+        let at_turbo = actual_level >= turbo_from && actual_level > sustained;
+        if !crate::turbo::update(at_turbo).await && at_turbo && !turbo_spent {
+            info!("Dropping to sustained level {}", sustained);
+            turbo_spent = true;
+        }
+        if desired_level <= sustained {
+            turbo_spent = false;
+        }
+        if turbo_spent {
+            actual_level = actual_level.min(sustained);
+        }
+
+        actual_level = flash.apply(actual_level);
+
+        // charge for however long the previous level actually ran, the loop
+        // can take longer than a tick when other tasks are busy
+        let now = Instant::now();
+        crate::energy::account(previous_level, volts, now.duration_since(last_tick)).await;
+        last_tick = now;
 
         if actual_level != previous_level {
             previous_level = actual_level;
 
             paths.set(actual_level).await;
+            *OUTPUT_LEVEL.lock().await = actual_level;
         }
 
         if actual_level == 0 && desired_level == 0 {
             return;
         }
 
-        maitake::time::sleep(Duration::from_hz(TICKS_PER_SEC).into()).await;
+        maitake::time::sleep(TICK).await;
     }
 }
 
 // #[embassy_executor::task]
-pub async fn power_task(
-    hdr: pins::hdr!(),
-    opamp_en: pins::opamp_en!(),
-    boost_en: pins::boost_en!(),
-    shunt_select: pins::shunt_select!(),
-    dac: DAC1,
-    dac_out: pins::dac!(),
-    pa5: PA5,
-) {
-    let mut hdr = hdr.into_ref();
-    let mut opamp_en = opamp_en.into_ref();
-    let mut boost_en = boost_en.into_ref();
-    let mut shunt_select = shunt_select.into_ref();
-    let mut dac = dac.into_ref();
-    let mut dac_out = dac_out.into_ref();
-    let mut pa5 = pa5.into_ref();
+pub async fn power_task(mut driver: impl PowerDriver) {
+    crate::energy::init().await;
+
     loop {
         info!("Power task coming online");
 
-        {
-            let (mut dac_ch1, mut dac_ch2) = Dac::new(
-                dac.reborrow(),
-                NoDma,
-                NoDma,
-                dac_out.reborrow(),
-                pa5.reborrow(),
-            )
-            .split();
-            dac_ch2.set(Value::Bit8(0));
-            dac_ch1.set(Value::Bit8(0));
-            dac_ch2.set_enable(false);
-            dac_ch1.set_enable(false);
-            dac_ch1.set_output_buffer(false);
-
-            let paths = PowerPaths {
-                dac: dac_ch1,
-                hdr: Output::new(
-                    hdr.reborrow(),
-                    embassy_stm32::gpio::Level::Low,
-                    embassy_stm32::gpio::Speed::Low,
-                ),
-                opamp_en: Output::new(
-                    opamp_en.reborrow(),
-                    embassy_stm32::gpio::Level::Low,
-                    embassy_stm32::gpio::Speed::Low,
-                ),
-                boost_en: Output::new(
-                    boost_en.reborrow(),
-                    embassy_stm32::gpio::Level::Low,
-                    embassy_stm32::gpio::Speed::Low,
-                ),
-                shunt_select: Output::new(
-                    shunt_select.reborrow(),
-                    embassy_stm32::gpio::Level::Low,
-                    embassy_stm32::gpio::Speed::Low,
-                ),
-            };
-
-            handle_on_state(paths).await;
-        }
+        handle_on_state(driver.bring_up()).await;
+        crate::energy::persist().await;
 
         POKE_POWER_CONTROLLER.wait().await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn warning_flash_dips_twice_then_stops() {
+        let mut flash = WarningFlash::default();
+        assert_eq!(flash.apply(100), 100);
+
+        flash.start();
+        for expected in [25, 100, 25, 100, 100] {
+            for _ in 0..FLASH_DIP_TICKS {
+                assert_eq!(flash.apply(100), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn warning_flash_keeps_off_off() {
+        let mut flash = WarningFlash::default();
+        flash.start();
+
+        assert!((0..FLASH_TICKS).all(|_| flash.apply(0) == 0));
+    }
+}
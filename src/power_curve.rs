@@ -1 +1,21 @@
 include!(concat!(env!("OUT_DIR"), "/power_curve.rs"));
+
+/// The entry of [`POWER_LEVELS`] for a level from 1 to 255. Entry i of the
+/// table aims at (i + 1) / len of the curve, so level n picks the entry closest
+/// to n / 256, with 256 entries that's entry n - 1 and the top one goes unused.
+pub fn power_level(level: u8) -> &'static PowerLevel {
+    let idx = (level.max(1) as usize - 1) * POWER_LEVELS.len() / 256;
+    &POWER_LEVELS[idx]
+}
+
+/// The range and DAC code to use for `level` given the range the driver is
+/// currently in. Levels in the crossover between the ranges stay in the current
+/// range, so that small adjustments don't flip back and forth.
+pub fn setting_for(level: u8, high_range: bool) -> (bool, u16) {
+    let l = power_level(level);
+
+    match l.alt_dac {
+        Some(dac) if l.hdr != high_range => (high_range, dac),
+        _ => (l.hdr, l.dac),
+    }
+}
@@ -0,0 +1,164 @@
+use maitake::time::Duration;
+
+use crate::settings::Settings;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RampStyle {
+    Smooth,
+    Stepped,
+}
+
+impl RampStyle {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            RampStyle::Smooth => 0,
+            RampStyle::Stepped => 1,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(RampStyle::Smooth),
+            1 => Some(RampStyle::Stepped),
+            _ => None,
+        }
+    }
+
+    pub fn toggled(self) -> Self {
+        match self {
+            RampStyle::Smooth => RampStyle::Stepped,
+            RampStyle::Stepped => RampStyle::Smooth,
+        }
+    }
+}
+
+/// Which level a click from off turns on at.
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MemoryMode {
+    /// The last level used.
+    Automatic,
+    /// A level the user saved.
+    Manual,
+    /// The last level used if the light was turned off recently, otherwise
+    /// the saved level.
+    Hybrid,
+}
+
+impl MemoryMode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            MemoryMode::Automatic => 0,
+            MemoryMode::Manual => 1,
+            MemoryMode::Hybrid => 2,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(MemoryMode::Automatic),
+            1 => Some(MemoryMode::Manual),
+            2 => Some(MemoryMode::Hybrid),
+            _ => None,
+        }
+    }
+}
+
+/// How holding the button moves through the levels.
+#[derive(Clone, Copy)]
+pub struct Ramp {
+    pub style: RampStyle,
+    pub steps: u8,
+    pub floor: u8,
+    pub ceiling: u8,
+}
+
+impl Ramp {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            style: settings.ramp_style,
+            steps: settings.ramp_steps.max(2),
+            floor: settings.ramp_floor.max(1),
+            ceiling: settings.ramp_ceiling.max(settings.ramp_floor.max(1)),
+        }
+    }
+
+    /// How long a hold waits between each adjustment.
+    pub fn interval(&self) -> Duration {
+        match self.style {
+            RampStyle::Smooth => Duration::from_millis(16),
+            RampStyle::Stepped => Duration::from_millis(400),
+        }
+    }
+
+    pub fn clamp(&self, level: u8) -> u8 {
+        level.clamp(self.floor, self.ceiling)
+    }
+
+    /// Move `level` by `delta` levels, staying between the floor and
+    /// ceiling. For modes that adjust their level freely rather than
+    /// following the ramp style.
+    pub fn nudge(&self, level: u8, delta: i8) -> u8 {
+        self.clamp(level.saturating_add_signed(delta))
+    }
+
+    /// Move one increment up or down the ramp from `level`.
+    pub fn adjust(&self, level: u8, direction: i8) -> u8 {
+        match self.style {
+            RampStyle::Smooth => self.nudge(level, direction),
+            RampStyle::Stepped => {
+                // levels off the steps (from the smooth ramp or the memory)
+                // go to the next step in that direction
+                let mut steps = (0..self.steps).map(|i| self.step(i));
+
+                if direction > 0 {
+                    steps.find(|&s| s > level).unwrap_or(level)
+                } else {
+                    steps.rev().find(|&s| s < level).unwrap_or(level)
+                }
+            }
+        }
+    }
+
+    // the power curve is already perceptually even, so evenly spacing the
+    // steps over the level indices spaces them evenly in brightness too
+    fn step(&self, i: u8) -> u8 {
+        let span = (self.ceiling - self.floor) as u16;
+        self.floor + (span * i as u16 / (self.steps - 1) as u16) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(style: RampStyle) -> Ramp {
+        Ramp {
+            style,
+            steps: 3,
+            floor: 10,
+            ceiling: 200,
+        }
+    }
+
+    #[test]
+    fn nudge_stays_between_floor_and_ceiling() {
+        let r = ramp(RampStyle::Smooth);
+
+        assert_eq!(r.nudge(12, -4), 10);
+        assert_eq!(r.nudge(10, -1), 10);
+        assert_eq!(r.nudge(198, 4), 200);
+        assert_eq!(r.nudge(255, 1), 200);
+        assert_eq!(r.nudge(50, 4), 54);
+    }
+
+    #[test]
+    fn stepped_moves_to_the_next_step() {
+        let r = ramp(RampStyle::Stepped);
+
+        assert_eq!(r.adjust(10, 1), 105);
+        assert_eq!(r.adjust(60, 1), 105);
+        assert_eq!(r.adjust(60, -1), 10);
+        assert_eq!(r.adjust(200, 1), 200);
+        assert_eq!(r.adjust(10, -1), 10);
+    }
+}
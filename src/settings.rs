@@ -0,0 +1,731 @@
+use defmt::{info, warn};
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+
+use crate::{
+    battery_level::{Chemistry, DEFAULT_CHEMISTRY},
+    button_led::{Pattern as ButtonLedPattern, MAX_BRIGHTNESS},
+    ramp::{MemoryMode, RampStyle},
+};
+
+// The settings record lives in the data EEPROM in one of two slots, each
+// write goes to the slot not holding the newest record so that a reset
+// mid-write never loses the previous settings.
+//
+// slot layout:
+//   magic: u16, seq: u16, version: u8, len: u8, crc: u16, payload: [u8; len]
+//
+// The payload is a flat list of fields, fields are only ever appended. When
+// loading a record written by an older version the fields it doesn't have are
+// filled in from the factory defaults, then `Settings::migrate` gets a chance
+// to fix up anything whose meaning changed.
+
+const MAGIC: u16 = 0x7459;
+const HEADER_LEN: usize = 8;
+const SLOT_LEN: usize = 64;
+const PAYLOAD_CAP: usize = SLOT_LEN - HEADER_LEN;
+const SLOTS: [usize; 2] = [0, SLOT_LEN];
+
+// bumped once per released firmware whose record format differs from the
+// last release, not for every field added in between
+pub const VERSION: u8 = 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Settings {
+    pub default_level: u8,
+    pub saved_level: u8,
+    pub unlocked: bool,
+    /// Temperature the thermal regulator holds the head at, in degrees.
+    pub max_temp: u8,
+    pub battery: Chemistry,
+    /// Added to the internal temperature sensor reading, set by the thermal
+    /// calibration, in degrees.
+    pub temp_offset: i8,
+    pub ramp_style: RampStyle,
+    /// Number of steps in the stepped ramp.
+    pub ramp_steps: u8,
+    pub ramp_floor: u8,
+    pub ramp_ceiling: u8,
+    /// How long the light sits unlocked and off before locking itself, zero
+    /// never locks.
+    pub autolock_mins: u8,
+    /// Colour of the aux LEDs while unlocked and off, zero shows the battery
+    /// level, otherwise a fixed hue.
+    pub aux_colour: u8,
+    /// Charge used since the battery was last charged, in mAh.
+    pub used_mah: u16,
+    pub capacity_mah: u16,
+    /// Level the light drops to once the turbo budget is spent.
+    pub turbo_sustained_level: u8,
+    pub memory_mode: MemoryMode,
+    /// Level saved by the user for manual and hybrid memory.
+    pub manual_level: u8,
+    /// How long after turning off hybrid memory still uses the last level.
+    pub hybrid_timeout_mins: u8,
+    /// Level the light runs at while the button is held in lockout, zero
+    /// disables it.
+    pub lockout_level: u8,
+    /// How long each beacon flash lasts, in tenths of a second.
+    pub beacon_on_tenths: u8,
+    /// Time from one beacon flash to the next, in seconds.
+    pub beacon_interval_secs: u8,
+    /// Lowercase letters blinked out by the message beacon, nul padded.
+    pub morse_message: [u8; 8],
+    pub debounce_ms: u8,
+    /// How long after a release another press still counts as the same
+    /// click.
+    pub click_window_ms: u16,
+    /// How long a press has to last to be a hold.
+    pub hold_ms: u16,
+    /// Milliseconds on per 4ms frame, zero keeps the button LED off. Anything
+    /// between off and full needs software PWM, which keeps the MCU awake.
+    pub button_led_brightness: u8,
+    pub button_led_locked: ButtonLedPattern,
+    pub button_led_unlocked: ButtonLedPattern,
+    /// Resting battery voltage the last time the light turned on, in mV.
+    pub resting_mv: u16,
+}
+
+impl Settings {
+    pub const fn factory_default() -> Self {
+        Self {
+            default_level: 27,
+            saved_level: 27,
+            unlocked: false,
+            max_temp: 40,
+            battery: DEFAULT_CHEMISTRY,
+            temp_offset: 0,
+            ramp_style: RampStyle::Smooth,
+            ramp_steps: 7,
+            ramp_floor: 1,
+            ramp_ceiling: 255,
+            autolock_mins: 3,
+            aux_colour: 0,
+            used_mah: 0,
+            capacity_mah: 3000,
+            turbo_sustained_level: 150,
+            memory_mode: MemoryMode::Automatic,
+            manual_level: 27,
+            hybrid_timeout_mins: 10,
+            lockout_level: 30,
+            beacon_on_tenths: 2,
+            beacon_interval_secs: 5,
+            morse_message: *b"tyrfing\0",
+            debounce_ms: 16,
+            click_window_ms: 300,
+            hold_ms: 300,
+            button_led_brightness: MAX_BRIGHTNESS,
+            button_led_locked: ButtonLedPattern::Locator,
+            button_led_unlocked: ButtonLedPattern::Steady,
+            resting_mv: 0,
+        }
+    }
+
+    fn encode(&self, w: &mut Writer) {
+        w.u8(self.default_level);
+        w.u8(self.saved_level);
+        w.bool(self.unlocked);
+        w.u8(self.max_temp);
+        w.u8(self.battery.to_u8());
+        w.u8(self.temp_offset as u8);
+        w.u8(self.ramp_style.to_u8());
+        w.u8(self.ramp_steps);
+        w.u8(self.ramp_floor);
+        w.u8(self.ramp_ceiling);
+        w.u8(self.autolock_mins);
+        w.u8(self.aux_colour);
+        w.u16(self.used_mah);
+        w.u16(self.capacity_mah);
+        w.u8(self.turbo_sustained_level);
+        w.u8(self.memory_mode.to_u8());
+        w.u8(self.manual_level);
+        w.u8(self.hybrid_timeout_mins);
+        w.u8(self.lockout_level);
+        w.u8(self.beacon_on_tenths);
+        w.u8(self.beacon_interval_secs);
+        w.bytes(&self.morse_message);
+        w.u8(self.debounce_ms);
+        w.u16(self.click_window_ms);
+        w.u16(self.hold_ms);
+        w.u8(self.button_led_brightness);
+        w.u8(self.button_led_locked.to_u8());
+        w.u8(self.button_led_unlocked.to_u8());
+        w.u16(self.resting_mv);
+    }
+
+    fn decode(r: &mut Reader) -> Self {
+        let d = Self::factory_default();
+
+        Self {
+            default_level: r.u8().unwrap_or(d.default_level),
+            saved_level: r.u8().unwrap_or(d.saved_level),
+            unlocked: r.bool().unwrap_or(d.unlocked),
+            max_temp: r.u8().unwrap_or(d.max_temp),
+            battery: r.u8().and_then(Chemistry::from_u8).unwrap_or(d.battery),
+            temp_offset: r.u8().map(|v| v as i8).unwrap_or(d.temp_offset),
+            ramp_style: r.u8().and_then(RampStyle::from_u8).unwrap_or(d.ramp_style),
+            ramp_steps: r.u8().unwrap_or(d.ramp_steps),
+            ramp_floor: r.u8().unwrap_or(d.ramp_floor),
+            ramp_ceiling: r.u8().unwrap_or(d.ramp_ceiling),
+            autolock_mins: r.u8().unwrap_or(d.autolock_mins),
+            aux_colour: r.u8().unwrap_or(d.aux_colour),
+            used_mah: r.u16().unwrap_or(d.used_mah),
+            capacity_mah: r.u16().unwrap_or(d.capacity_mah),
+            turbo_sustained_level: r.u8().unwrap_or(d.turbo_sustained_level),
+            memory_mode: r
+                .u8()
+                .and_then(MemoryMode::from_u8)
+                .unwrap_or(d.memory_mode),
+            manual_level: r.u8().unwrap_or(d.manual_level),
+            hybrid_timeout_mins: r.u8().unwrap_or(d.hybrid_timeout_mins),
+            lockout_level: r.u8().unwrap_or(d.lockout_level),
+            beacon_on_tenths: r.u8().unwrap_or(d.beacon_on_tenths),
+            beacon_interval_secs: r.u8().unwrap_or(d.beacon_interval_secs),
+            morse_message: r.bytes().unwrap_or(d.morse_message),
+            debounce_ms: r.u8().unwrap_or(d.debounce_ms),
+            click_window_ms: r.u16().unwrap_or(d.click_window_ms),
+            hold_ms: r.u16().unwrap_or(d.hold_ms),
+            button_led_brightness: r.u8().unwrap_or(d.button_led_brightness),
+            button_led_locked: r
+                .u8()
+                .and_then(ButtonLedPattern::from_u8)
+                .unwrap_or(d.button_led_locked),
+            button_led_unlocked: r
+                .u8()
+                .and_then(ButtonLedPattern::from_u8)
+                .unwrap_or(d.button_led_unlocked),
+            resting_mv: r.u16().unwrap_or(d.resting_mv),
+        }
+    }
+
+    /// Fix up settings loaded from a record written by an older firmware.
+    fn migrate(self, from: u8) -> Self {
+        if from != VERSION {
+            info!("Migrating settings from v{} to v{}", from, VERSION);
+        }
+
+        self
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::factory_default()
+    }
+}
+
+struct Writer {
+    buf: [u8; PAYLOAD_CAP],
+    len: usize,
+}
+
+impl Writer {
+    fn new() -> Self {
+        Self {
+            buf: [0; PAYLOAD_CAP],
+            len: 0,
+        }
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buf[self.len] = v;
+        self.len += 1;
+    }
+
+    fn bool(&mut self, v: bool) {
+        self.u8(v as u8);
+    }
+
+    fn u16(&mut self, v: u16) {
+        for b in v.to_le_bytes() {
+            self.u8(b);
+        }
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        for &b in v {
+            self.u8(b);
+        }
+    }
+
+    fn payload(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Option<u8> {
+        let (v, rest) = self.buf.split_first()?;
+        self.buf = rest;
+        Some(*v)
+    }
+
+    fn bool(&mut self) -> Option<bool> {
+        self.u8().map(|v| v != 0)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        if self.buf.len() < 2 {
+            return None;
+        }
+        let v = u16::from_le_bytes([self.buf[0], self.buf[1]]);
+        self.buf = &self.buf[2..];
+        Some(v)
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (v, rest) = self.buf.split_first_chunk::<N>()?;
+        self.buf = rest;
+        Some(*v)
+    }
+}
+
+// CRC-16/CCITT-FALSE
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+fn record_crc(version: u8, payload: &[u8]) -> u16 {
+    let mut buf = [0u8; 2 + PAYLOAD_CAP];
+    buf[0] = version;
+    buf[1] = payload.len() as u8;
+    buf[2..2 + payload.len()].copy_from_slice(payload);
+
+    crc16(&buf[..2 + payload.len()])
+}
+
+/// Byte addressed non-volatile storage that the settings are persisted into.
+pub trait Eeprom {
+    fn read(&mut self, offset: usize, buf: &mut [u8]);
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EepromError>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, defmt::Format)]
+pub enum EepromError {
+    OutOfRange,
+    Write,
+}
+
+/// An EEPROM backed by RAM, for running the settings logic off the device.
+pub struct MemEeprom<const N: usize> {
+    pub data: [u8; N],
+}
+
+impl<const N: usize> MemEeprom<N> {
+    pub const fn new() -> Self {
+        // erased EEPROM on the L0 reads as zeros
+        Self { data: [0; N] }
+    }
+}
+
+impl<const N: usize> Default for MemEeprom<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Eeprom for MemEeprom<N> {
+    fn read(&mut self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.data[offset..offset + buf.len()]);
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), EepromError> {
+        self.data
+            .get_mut(offset..offset + data.len())
+            .ok_or(EepromError::OutOfRange)?
+            .copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Header {
+    seq: u16,
+    version: u8,
+    len: u8,
+    crc: u16,
+}
+
+impl Header {
+    fn parse(b: &[u8; HEADER_LEN]) -> Option<Self> {
+        if u16::from_le_bytes([b[0], b[1]]) != MAGIC {
+            return None;
+        }
+
+        let header = Self {
+            seq: u16::from_le_bytes([b[2], b[3]]),
+            version: b[4],
+            len: b[5],
+            crc: u16::from_le_bytes([b[6], b[7]]),
+        };
+
+        if header.len as usize > PAYLOAD_CAP {
+            return None;
+        }
+
+        Some(header)
+    }
+
+    fn to_bytes(self) -> [u8; HEADER_LEN] {
+        let [m0, m1] = MAGIC.to_le_bytes();
+        let [s0, s1] = self.seq.to_le_bytes();
+        let [c0, c1] = self.crc.to_le_bytes();
+        [m0, m1, s0, s1, self.version, self.len, c0, c1]
+    }
+}
+
+/// Settings persistence on top of an [`Eeprom`].
+pub struct Store<E> {
+    eeprom: E,
+    // slot index and sequence number of the newest valid record
+    newest: Option<(usize, u16)>,
+    last_written: Option<Settings>,
+}
+
+impl<E: Eeprom> Store<E> {
+    pub fn new(eeprom: E) -> Self {
+        Self {
+            eeprom,
+            newest: None,
+            last_written: None,
+        }
+    }
+
+    fn read_slot(&mut self, slot: usize) -> Option<(Header, Settings)> {
+        let mut buf = [0u8; SLOT_LEN];
+        self.eeprom.read(SLOTS[slot], &mut buf);
+
+        let header = Header::parse(buf[..HEADER_LEN].try_into().unwrap())?;
+        let payload = &buf[HEADER_LEN..HEADER_LEN + header.len as usize];
+
+        if record_crc(header.version, payload) != header.crc {
+            warn!("Settings slot {} failed its CRC check", slot);
+            return None;
+        }
+
+        let settings = Settings::decode(&mut Reader { buf: payload });
+
+        Some((header, settings.migrate(header.version)))
+    }
+
+    /// Load the newest valid settings record, falling back to the factory
+    /// defaults if there are none.
+    pub fn load(&mut self) -> Settings {
+        let newest = (0..SLOTS.len())
+            .filter_map(|slot| self.read_slot(slot).map(|(h, s)| (slot, h, s)))
+            .reduce(|a, b| {
+                // sequence numbers wrap, the newer one is the one that is
+                // less than half the range ahead
+                if b.1.seq.wrapping_sub(a.1.seq) < 0x8000 {
+                    b
+                } else {
+                    a
+                }
+            });
+
+        match newest {
+            Some((slot, header, settings)) => {
+                info!("Loaded settings v{} from slot {}", header.version, slot);
+                self.newest = Some((slot, header.seq));
+                // a record from older firmware gets rewritten on the next
+                // store even if nothing changed
+                self.last_written = (header.version == VERSION).then_some(settings);
+                settings
+            }
+            None => {
+                info!("No valid settings found, using factory defaults");
+                self.newest = None;
+                self.last_written = None;
+                Settings::factory_default()
+            }
+        }
+    }
+
+    /// Persist `settings`, skipping the write if nothing has changed.
+    pub fn store(&mut self, settings: &Settings) -> Result<(), EepromError> {
+        if self.last_written.as_ref() == Some(settings) {
+            return Ok(());
+        }
+
+        let mut w = Writer::new();
+        settings.encode(&mut w);
+        let payload = w.payload();
+
+        let (slot, seq) = match self.newest {
+            Some((slot, seq)) => ((slot + 1) % SLOTS.len(), seq.wrapping_add(1)),
+            None => (0, 0),
+        };
+
+        let header = Header {
+            seq,
+            version: VERSION,
+            len: payload.len() as u8,
+            crc: record_crc(VERSION, payload),
+        };
+
+        // the payload goes down first so that the slot only becomes valid
+        // once the header (and so the crc) is written
+        self.eeprom.write(SLOTS[slot] + HEADER_LEN, payload)?;
+        self.eeprom.write(SLOTS[slot], &header.to_bytes())?;
+
+        self.newest = Some((slot, seq));
+        self.last_written = Some(*settings);
+
+        Ok(())
+    }
+}
+
+static SETTINGS: Mutex<ThreadModeRawMutex, Settings> = Mutex::new(Settings::factory_default());
+
+static POKE_SETTINGS: embassy_sync::signal::Signal<ThreadModeRawMutex, ()> =
+    embassy_sync::signal::Signal::new();
+
+/// Install the settings loaded at boot, must be called before the executor
+/// starts running.
+pub fn init(settings: Settings) {
+    *SETTINGS.try_lock().unwrap() = settings;
+}
+
+pub async fn get() -> Settings {
+    *SETTINGS.lock().await
+}
+
+/// Modify the settings, they get written back to the EEPROM shortly after.
+pub async fn update(f: impl FnOnce(&mut Settings)) {
+    f(&mut *SETTINGS.lock().await);
+    POKE_SETTINGS.signal(());
+}
+
+// #[embassy_executor::task]
+pub async fn settings_task<E: Eeprom>(mut store: Store<E>) {
+    loop {
+        POKE_SETTINGS.wait().await;
+
+        // batch up bursts of changes (e.g. while ramping) into one write
+        while maitake::time::timeout(core::time::Duration::from_secs(2), POKE_SETTINGS.wait())
+            .await
+            .is_ok()
+        {}
+
+        let settings = get().await;
+        if let Err(e) = store.store(&settings) {
+            warn!("Failed to persist settings: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Mem = MemEeprom<{ 2 * SLOT_LEN }>;
+
+    fn changed() -> Settings {
+        Settings {
+            default_level: 80,
+            unlocked: true,
+            temp_offset: -3,
+            ramp_style: RampStyle::Stepped,
+            used_mah: 1234,
+            morse_message: *b"sos\0\0\0\0\0",
+            hold_ms: 450,
+            button_led_unlocked: ButtonLedPattern::Breathe,
+            ..Settings::factory_default()
+        }
+    }
+
+    fn header(store: &Store<Mem>, slot: usize) -> Option<Header> {
+        Header::parse(
+            store.eeprom.data[SLOTS[slot]..][..HEADER_LEN]
+                .try_into()
+                .unwrap(),
+        )
+    }
+
+    // writes a record the way an older firmware would have
+    fn write_record(eeprom: &mut Mem, slot: usize, seq: u16, version: u8, payload: &[u8]) {
+        let header = Header {
+            seq,
+            version,
+            len: payload.len() as u8,
+            crc: record_crc(version, payload),
+        };
+
+        eeprom.write(SLOTS[slot] + HEADER_LEN, payload).unwrap();
+        eeprom.write(SLOTS[slot], &header.to_bytes()).unwrap();
+    }
+
+    fn encoded(settings: &Settings) -> ([u8; PAYLOAD_CAP], usize) {
+        let mut w = Writer::new();
+        settings.encode(&mut w);
+        (w.buf, w.len)
+    }
+
+    #[test]
+    fn blank_eeprom_loads_defaults() {
+        let mut store = Store::new(Mem::new());
+
+        assert!(store.load() == Settings::factory_default());
+        assert!(store.newest.is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut store = Store::new(Mem::new());
+        store.load();
+        store.store(&changed()).unwrap();
+
+        let mut reloaded = Store::new(Mem {
+            data: store.eeprom.data,
+        });
+        assert!(reloaded.load() == changed());
+    }
+
+    #[test]
+    fn payload_fits_a_slot() {
+        let (_, len) = encoded(&changed());
+        assert!(len <= PAYLOAD_CAP);
+    }
+
+    #[test]
+    fn unchanged_settings_are_not_rewritten() {
+        let mut store = Store::new(Mem::new());
+        store.load();
+        store.store(&changed()).unwrap();
+        store.store(&changed()).unwrap();
+
+        assert_eq!(store.newest, Some((0, 0)));
+        assert!(header(&store, 1).is_none());
+    }
+
+    #[test]
+    fn writes_alternate_slots() {
+        let mut store = Store::new(Mem::new());
+        store.load();
+
+        for i in 0..5u8 {
+            let settings = Settings {
+                default_level: i,
+                ..changed()
+            };
+            store.store(&settings).unwrap();
+
+            let slot = i as usize % 2;
+            assert_eq!(store.newest, Some((slot, i as u16)));
+            assert_eq!(header(&store, slot).unwrap().seq, i as u16);
+
+            let mut reloaded = Store::new(Mem {
+                data: store.eeprom.data,
+            });
+            assert_eq!(reloaded.load().default_level, i);
+        }
+    }
+
+    #[test]
+    fn corrupt_newest_falls_back_to_older_slot() {
+        let mut store = Store::new(Mem::new());
+        store.load();
+        store.store(&Settings::factory_default()).unwrap();
+        store.store(&changed()).unwrap();
+
+        // flip a payload bit in the newest record (slot 1)
+        let mut data = store.eeprom.data;
+        data[SLOTS[1] + HEADER_LEN] ^= 0x01;
+
+        let mut reloaded = Store::new(Mem { data });
+        assert!(reloaded.load() == Settings::factory_default());
+        assert_eq!(reloaded.newest, Some((0, 0)));
+
+        // and the next write replaces the corrupt slot rather than the good one
+        reloaded.store(&changed()).unwrap();
+        assert_eq!(reloaded.newest, Some((1, 1)));
+    }
+
+    #[test]
+    fn both_slots_corrupt_loads_defaults() {
+        let mut store = Store::new(Mem::new());
+        store.load();
+        store.store(&changed()).unwrap();
+        store
+            .store(&Settings {
+                default_level: 1,
+                ..changed()
+            })
+            .unwrap();
+
+        let mut data = store.eeprom.data;
+        data[SLOTS[0] + HEADER_LEN + 3] ^= 0x80;
+        // a bad crc in the header counts as well
+        data[SLOTS[1] + 6] ^= 0xff;
+
+        let mut reloaded = Store::new(Mem { data });
+        assert!(reloaded.load() == Settings::factory_default());
+        assert!(reloaded.newest.is_none());
+    }
+
+    #[test]
+    fn sequence_numbers_wrap() {
+        let mut eeprom = Mem::new();
+        let (payload, len) = encoded(&Settings::factory_default());
+        write_record(&mut eeprom, 1, 0xffff, VERSION, &payload[..len]);
+
+        let mut store = Store::new(eeprom);
+        store.load();
+        assert_eq!(store.newest, Some((1, 0xffff)));
+
+        store.store(&changed()).unwrap();
+        assert_eq!(store.newest, Some((0, 0)));
+
+        // seq 0 in slot 0 is newer than 0xffff in slot 1
+        let mut reloaded = Store::new(Mem {
+            data: store.eeprom.data,
+        });
+        assert!(reloaded.load() == changed());
+        assert_eq!(reloaded.newest, Some((0, 0)));
+    }
+
+    #[test]
+    fn old_records_get_new_fields_from_defaults() {
+        // a record from before the fields after the lockout level were added
+        let old = Settings {
+            default_level: 90,
+            lockout_level: 12,
+            ..Settings::factory_default()
+        };
+        let (payload, _) = encoded(&old);
+
+        let mut eeprom = Mem::new();
+        write_record(&mut eeprom, 0, 3, VERSION - 1, &payload[..21]);
+
+        let mut store = Store::new(eeprom);
+        let loaded = store.load();
+
+        assert_eq!(loaded.default_level, 90);
+        assert_eq!(loaded.lockout_level, 12);
+        assert_eq!(loaded.beacon_interval_secs, 5);
+        assert_eq!(loaded.morse_message, *b"tyrfing\0");
+        assert_eq!(loaded.hold_ms, 300);
+        assert!(loaded.button_led_locked == ButtonLedPattern::Locator);
+
+        // the next write upgrades it in the other slot
+        store.store(&loaded).unwrap();
+        assert_eq!(store.newest, Some((1, 4)));
+        assert_eq!(header(&store, 1).unwrap().version, VERSION);
+    }
+}
@@ -1,5 +1,5 @@
-use embassy_stm32::gpio::Output;
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use maitake::time::Instant;
 
 use crate::aux::poke_aux;
 
@@ -21,17 +21,17 @@ pub async fn is_unlocked() -> bool {
 
 pub async fn set_unlocked(unlocked: bool) {
     *UNLOCKED.lock().await = unlocked;
+    crate::settings::update(|s| s.unlocked = unlocked).await;
     poke_aux();
 }
 
-pub fn emergency_stop() {
-    let en_pin = unsafe { embassy_stm32::peripherals::PA3::steal() };
-    let mut en_pin = Output::new(
-        en_pin,
-        embassy_stm32::gpio::Level::Low,
-        embassy_stm32::gpio::Speed::Low,
-    );
-    en_pin.set_low();
+static AUTOLOCK_DEADLINE: Mutex<ThreadModeRawMutex, Option<Instant>> = Mutex::new(None);
 
-    cortex_m::peripheral::SCB::sys_reset();
+/// When the light will lock itself if left alone, if it's counting down.
+pub async fn autolock_deadline() -> Option<Instant> {
+    *AUTOLOCK_DEADLINE.lock().await
+}
+
+pub async fn set_autolock_deadline(deadline: Option<Instant>) {
+    *AUTOLOCK_DEADLINE.lock().await = deadline;
 }
@@ -0,0 +1,40 @@
+// Bits the host tests need that the firmware gets from its runtime.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Once,
+};
+
+use maitake::time::{Clock, Duration, Instant, Timer};
+
+#[defmt::global_logger]
+struct Logger;
+
+// nothing reads defmt output on the host
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
+
+// the clock never moves, tests build later instants by adding to `now()`
+static NOW_MS: AtomicU64 = AtomicU64::new(0);
+
+/// An instant to start a test from, installing a global timer on first use.
+pub fn now() -> Instant {
+    static TIMER: Once = Once::new();
+
+    TIMER.call_once(|| {
+        let clock = Clock::new(Duration::from_millis(1), || NOW_MS.load(Ordering::Relaxed));
+        let timer: &'static Timer = Box::leak(Box::new(Timer::new(clock)));
+        maitake::time::set_global_timer(timer).unwrap();
+    });
+
+    Instant::now()
+}
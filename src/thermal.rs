@@ -0,0 +1,196 @@
+use fixed::types::I16F16;
+use fixed_macro::types::I16F16;
+
+use crate::monitoring::Temp;
+
+// PID gains, in levels per degree
+const KP: I16F16 = I16F16!(24.0);
+// levels per degree second
+const KI: I16F16 = I16F16!(0.5);
+// levels per degree/second of temperature slope
+const KD: I16F16 = I16F16!(20.0);
+
+// never regulate below this level, the instant stop temperature exists for
+// when things have gotten out of hand
+const MIN_LEVEL: I16F16 = I16F16!(20.0);
+const MAX_LEVEL: I16F16 = I16F16!(255.0);
+
+/// Highest ceiling the user can pick. Power cuts the light outright at 50°C,
+/// this leaves the regulator room to overshoot a little before that.
+pub const MAX_CEILING: u8 = 45;
+
+// how quickly the limit may rise again once the head has cooled, in levels per
+// second
+const MAX_RISE_RATE: I16F16 = I16F16!(10.0);
+
+/// Limits the output level to hold the head at a ceiling temperature.
+///
+/// The error is the headroom below the ceiling, the derivative term uses the
+/// temperature slope estimated by the Kalman filter in `monitoring` rather
+/// than differentiating the noisy readings. The integral only winds up as far
+/// as it takes to reach full output, so that it doesn't build up while the
+/// head is heating and overshoot the ceiling. It starts out at full output.
+pub struct ThermalRegulator {
+    ceiling: Temp,
+    integral: I16F16,
+    limit: I16F16,
+}
+
+impl ThermalRegulator {
+    pub fn new(ceiling: Temp) -> Self {
+        Self {
+            ceiling,
+            integral: MAX_LEVEL,
+            limit: MAX_LEVEL,
+        }
+    }
+
+    /// Feed in the current temperature and its slope (in degrees per second)
+    /// after `dt` seconds, returns the highest level the light may run at.
+    pub fn update(&mut self, temp: Temp, slope: I16F16, dt: I16F16) -> u8 {
+        let error = self.ceiling.0 - temp.0;
+
+        let proportional = KP.saturating_mul(error);
+        let derivative = KD.saturating_mul(slope);
+
+        // only wind up as far as it takes to reach full output, while the
+        // head is heating up at full power the integral would otherwise keep
+        // growing and still be far too high by the time it hits the ceiling
+        let headroom = MAX_LEVEL
+            .saturating_sub(proportional)
+            .saturating_add(derivative);
+        self.integral = (self.integral + KI * error * dt)
+            .min(headroom)
+            .clamp(MIN_LEVEL, MAX_LEVEL);
+
+        let output = proportional
+            .saturating_add(self.integral)
+            .saturating_sub(derivative)
+            .clamp(MIN_LEVEL, MAX_LEVEL);
+
+        // step down as hard as needed, but come back up gently
+        self.limit = output.min(self.limit + MAX_RISE_RATE * dt);
+
+        self.limit.to_num()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.25;
+    const AMBIENT: f32 = 22.0;
+
+    // A first-order model of the head: it heads towards ambient plus a rise
+    // proportional to output power, with a 60s time constant. Full power
+    // would settle 40 degrees above ambient, well past any ceiling.
+    struct Head {
+        temp: f32,
+    }
+
+    impl Head {
+        const FULL_POWER_RISE: f32 = 40.0;
+        const TAU: f32 = 60.0;
+
+        // the slope the head is heading at, in degrees per second
+        fn slope(&self, level: u8) -> f32 {
+            let power = (level as f32 / 255.0).powi(2);
+            (AMBIENT + Self::FULL_POWER_RISE * power - self.temp) / Self::TAU
+        }
+
+        // steps the model by DT with the light at `level`, returns the new
+        // limit
+        fn step(&mut self, regulator: &mut ThermalRegulator, level: u8) -> u8 {
+            let slope = self.slope(level);
+            self.temp += slope * DT;
+
+            regulator.update(
+                Temp(I16F16::from_num(self.temp)),
+                I16F16::from_num(slope),
+                I16F16::from_num(DT),
+            )
+        }
+    }
+
+    fn steps(secs: f32) -> usize {
+        (secs / DT) as usize
+    }
+
+    fn regulator(ceiling: u8) -> ThermalRegulator {
+        ThermalRegulator::new(Temp(I16F16::from_num(ceiling)))
+    }
+
+    #[test]
+    fn settles_below_the_ceiling() {
+        for ceiling in [30, 40, MAX_CEILING] {
+            let mut head = Head { temp: AMBIENT };
+            let mut regulator = regulator(ceiling);
+            let mut level = 255;
+            let mut hottest = AMBIENT;
+
+            for _ in 0..steps(20.0 * 60.0) {
+                level = head.step(&mut regulator, level);
+                hottest = hottest.max(head.temp);
+            }
+
+            let ceiling = ceiling as f32;
+            assert!(hottest < ceiling + 0.5, "overshot {ceiling} to {hottest}");
+            // and doesn't throttle much harder than it has to
+            assert!(head.temp > ceiling - 1.0, "settled at {}", head.temp);
+            assert!(head.slope(level).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn recovers_quickly_after_a_hot_soak() {
+        let mut regulator = regulator(40);
+
+        // the head is held hot from outside for ten minutes, pinning the
+        // limit and the integral to the bottom
+        let mut head = Head { temp: 48.0 };
+        for _ in 0..steps(10.0 * 60.0) {
+            head.temp = 48.0;
+            head.step(&mut regulator, 0);
+        }
+        assert_eq!(head.step(&mut regulator, 0), MIN_LEVEL.to_num::<u8>());
+
+        // once it's back at ambient full output comes back as fast as the
+        // rise rate allows, rather than waiting for a wound up integral
+        head.temp = AMBIENT;
+        let rise_secs = ((MAX_LEVEL - MIN_LEVEL) / MAX_RISE_RATE).to_num::<f32>();
+        let mut limit = 0;
+        for _ in 0..steps(rise_secs + 2.0) {
+            head.temp = AMBIENT;
+            limit = head.step(&mut regulator, 0);
+        }
+
+        assert_eq!(limit, 255);
+    }
+
+    #[test]
+    fn limit_rises_gently_after_cooling() {
+        let mut head = Head { temp: AMBIENT };
+        let mut regulator = regulator(35);
+        let mut level = 255;
+
+        // run hot until throttled well down
+        for _ in 0..steps(10.0 * 60.0) {
+            level = head.step(&mut regulator, level);
+        }
+        assert!(level < 200);
+
+        // then turn off and let it cool, the limit climbs back to full
+        // without ever jumping more than the rise rate allows
+        let max_step = (MAX_RISE_RATE * I16F16::from_num(DT)).to_num::<f32>();
+        let mut previous = level;
+        let mut limit = level;
+        for _ in 0..steps(5.0 * 60.0) {
+            limit = head.step(&mut regulator, 0);
+            assert!(limit as f32 - previous as f32 <= max_step + 1.0);
+            previous = limit;
+        }
+
+        assert_eq!(limit, 255);
+    }
+}
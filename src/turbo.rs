@@ -0,0 +1,149 @@
+use defmt::info;
+use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use fixed::types::I16F16;
+use fixed_macro::types::I16F16;
+use maitake::time::Instant;
+
+// How long the light may run at turbo (the ramp ceiling), in seconds. Running
+// at turbo drains the budget, faster while the head is heating up, and it
+// refills at any lower level or while off.
+
+const MAX_BUDGET: I16F16 = I16F16!(60.0);
+// once spent, the budget has to refill this far before turbo is allowed again
+const MIN_TO_ENGAGE: I16F16 = I16F16!(15.0);
+// seconds of budget regained per second
+const RECOVERY_RATE: I16F16 = I16F16!(0.25);
+// extra seconds drained per second, per degree/second of temperature rise
+const SLOPE_WEIGHT: I16F16 = I16F16!(2.0);
+
+struct Budget {
+    secs: I16F16,
+    exhausted: bool,
+    last_update: Option<Instant>,
+}
+
+impl Budget {
+    // seconds since the last update, anything longer than it takes to refill
+    // completely may as well be that
+    fn since_update(&self) -> I16F16 {
+        let ms = self
+            .last_update
+            .map_or(0, |t| t.elapsed().as_millis().min(300_000) as u32);
+        I16F16::from_num(ms / 10) / I16F16!(100.0)
+    }
+
+    // what the budget would be after `dt` seconds away from turbo
+    fn recovered(&self, dt: I16F16, rising: I16F16) -> I16F16 {
+        // cooling down counts for less while the head is still heating up
+        let recovery =
+            dt.saturating_mul(RECOVERY_RATE) / (I16F16!(1.0) + rising.saturating_mul(SLOPE_WEIGHT));
+        (self.secs + recovery).min(MAX_BUDGET)
+    }
+
+    fn spend(&mut self, dt: I16F16, rising: I16F16, at_turbo: bool) {
+        if at_turbo {
+            let drain = dt.saturating_mul(I16F16!(1.0) + rising.saturating_mul(SLOPE_WEIGHT));
+            self.secs = (self.secs - drain).max(I16F16::ZERO);
+
+            if self.secs == I16F16::ZERO && !self.exhausted {
+                info!("Turbo budget spent");
+                self.exhausted = true;
+            }
+        } else {
+            self.secs = self.recovered(dt, rising);
+
+            if self.exhausted && self.secs >= MIN_TO_ENGAGE {
+                info!("Turbo budget recovered");
+                self.exhausted = false;
+            }
+        }
+    }
+
+    fn allows(&self, dt: I16F16, rising: I16F16) -> bool {
+        !self.exhausted || self.recovered(dt, rising) >= MIN_TO_ENGAGE
+    }
+}
+
+static BUDGET: Mutex<ThreadModeRawMutex, Budget> = Mutex::new(Budget {
+    secs: MAX_BUDGET,
+    exhausted: false,
+    last_update: None,
+});
+
+async fn rising() -> I16F16 {
+    crate::monitoring::TEMP_SLOPE.lock().await.max(I16F16::ZERO)
+}
+
+/// Account for the time since the last update, `at_turbo` is whether the
+/// light spent it at turbo. Returns whether turbo is still allowed.
+pub async fn update(at_turbo: bool) -> bool {
+    let rising = rising().await;
+    let mut budget = BUDGET.lock().await;
+
+    let dt = budget.since_update();
+    budget.last_update = Some(Instant::now());
+    budget.spend(dt, rising, at_turbo);
+
+    !budget.exhausted
+}
+
+/// Whether the budget allows going to turbo right now, counting what it has
+/// recovered since the last update without recording it.
+pub async fn can_engage() -> bool {
+    let rising = rising().await;
+    let budget = BUDGET.lock().await;
+
+    budget.allows(budget.since_update(), rising)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh() -> Budget {
+        Budget {
+            secs: MAX_BUDGET,
+            exhausted: false,
+            last_update: None,
+        }
+    }
+
+    #[test]
+    fn drains_faster_while_heating() {
+        let mut cool = fresh();
+        let mut heating = fresh();
+
+        cool.spend(I16F16!(10.0), I16F16::ZERO, true);
+        heating.spend(I16F16!(10.0), I16F16!(0.5), true);
+
+        assert_eq!(cool.secs, I16F16!(50.0));
+        assert_eq!(heating.secs, I16F16!(40.0));
+    }
+
+    #[test]
+    fn below_turbo_only_recovers() {
+        let mut budget = fresh();
+        budget.spend(I16F16!(20.0), I16F16::ZERO, true);
+        budget.spend(I16F16!(20.0), I16F16::ZERO, false);
+
+        assert_eq!(budget.secs, I16F16!(45.0));
+    }
+
+    #[test]
+    fn has_to_recover_before_engaging_again() {
+        let mut budget = fresh();
+        budget.spend(I16F16!(70.0), I16F16::ZERO, true);
+        assert!(budget.exhausted);
+        assert!(!budget.allows(I16F16::ZERO, I16F16::ZERO));
+
+        // asking doesn't count the recovery towards the budget
+        assert!(budget.allows(I16F16!(60.0), I16F16::ZERO));
+        assert!(budget.exhausted);
+        assert_eq!(budget.secs, I16F16::ZERO);
+
+        budget.spend(I16F16!(40.0), I16F16::ZERO, false);
+        assert!(budget.exhausted);
+        budget.spend(I16F16!(20.0), I16F16::ZERO, false);
+        assert!(!budget.exhausted);
+    }
+}
@@ -3,14 +3,22 @@ use core::{cell::Cell, future::Future, ops::ControlFlow};
 use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, signal::Signal};
 use maitake::time::{timeout, Duration, Instant};
 
+use defmt::info;
 use embassy_futures::select;
+use fixed::types::I16F16;
+use fixed_macro::types::I16F16;
 
 use crate::{
-    click::{ButtonEvent, ButtonState, BUTTON_EVENTS, LOCKOUT_BUTTON_STATES},
+    click::{
+        next_event, next_timestamped_event, subscribe_states, ButtonEvent, ButtonState, Timestamped,
+    },
     power::blink,
+    ramp::MemoryMode,
 };
 
-const DEFAULT_LEVEL: u8 = 27;
+#[cfg(any(feature = "mode_croak", feature = "mode_beacon"))]
+mod beacon;
+mod config_menu;
 
 enum Handled {
     Handled,
@@ -53,13 +61,22 @@ impl<H0: Handle, H1: Handle> Handle for (H0, H1) {
 impl<H: Handle> Handler<H> {
     async fn run(&mut self) {
         loop {
-            let r = self.inner.handle(BUTTON_EVENTS.wait().await).await;
+            let r = self.inner.handle(next_event().await).await;
             if let ControlFlow::Break(Handled::Exit) = r {
                 break;
             }
         }
     }
 
+    /// Like `run`, but also stops once no events have come in for `idle`.
+    async fn run_until_idle(&mut self, idle: Duration) {
+        while let Ok(e) = timeout(idle, next_event()).await {
+            if let ControlFlow::Break(Handled::Exit) = self.inner.handle(e).await {
+                break;
+            }
+        }
+    }
+
     fn and<Hother: Handle>(self, other: Hother) -> Handler<(H, Hother)> {
         Handler {
             inner: (self.inner, other),
@@ -97,6 +114,7 @@ where
 
 struct StandardAdjustment<Op> {
     last_hold_release: Instant,
+    interval: Duration,
     inner: Op,
 }
 
@@ -105,8 +123,13 @@ where
     Op: FnMut(i8),
 {
     fn new(op: Op) -> Self {
+        Self::with_interval(op, Duration::from_millis(16))
+    }
+
+    fn with_interval(op: Op, interval: Duration) -> Self {
         Self {
             last_hold_release: Instant::now(),
+            interval,
             inner: op,
         }
     }
@@ -118,35 +141,28 @@ where
 {
     async fn handle(&mut self, e: ButtonEvent) -> ControlFlow<Handled> {
         match e {
-            ButtonEvent::Click1 => ControlFlow::Break(Handled::Exit),
-            ButtonEvent::Hold1 => {
+            ButtonEvent::Click(1) => ControlFlow::Break(Handled::Exit),
+            ButtonEvent::Hold(1) => {
                 let direction = if self.last_hold_release.elapsed() > Duration::from_millis(500) {
                     1
                 } else {
                     -1
                 };
-                loop {
-                    if timeout(Duration::from_millis(16), BUTTON_EVENTS.wait())
-                        .await
-                        .is_err()
-                    {
-                        (self.inner)(direction);
-                    } else {
-                        break;
+                let released = loop {
+                    match timeout(self.interval, next_timestamped_event()).await {
+                        Ok(e) => break e.at,
+                        Err(_) => (self.inner)(direction),
                     }
-                }
+                };
                 if direction == 1 {
-                    self.last_hold_release = Instant::now();
+                    self.last_hold_release = released;
                 }
 
                 ControlFlow::Break(Handled::Handled)
             }
-            ButtonEvent::Hold2 => {
+            ButtonEvent::Hold(2) => {
                 loop {
-                    if timeout(Duration::from_millis(16), BUTTON_EVENTS.wait())
-                        .await
-                        .is_err()
-                    {
+                    if timeout(self.interval, next_event()).await.is_err() {
                         (self.inner)(-1);
                     } else {
                         break;
@@ -219,83 +235,255 @@ impl<const GRADUAL: bool, S: Copy> StateHandler<GRADUAL, S> {
     }
 }
 
-async fn with_torch_on<O>(fut: impl Future<Output = O>) -> O {
+/// Runs `fut` with the light on, returns `None` if the power controller had to
+/// turn the light off before it finished.
+async fn with_torch_on<O>(fut: impl Future<Output = O>) -> Option<O> {
+    crate::power::FORCE_OFF.reset();
     crate::state::set_on(true).await;
 
-    let r = fut.await;
+    let r = select::select(fut, crate::power::FORCE_OFF.wait()).await;
 
     crate::power::set_level_gradual(0).await;
     crate::state::set_on(false).await;
 
-    r
+    match r {
+        select::Either::First(r) => Some(r),
+        select::Either::Second(()) => {
+            info!("Forced off, locking");
+            crate::state::set_unlocked(false).await;
+            None
+        }
+    }
 }
 
 // #[embassy_executor::task]
 pub async fn torch_ui_task() {
-    let mut saved_level = DEFAULT_LEVEL;
+    crate::state::set_unlocked(crate::settings::get().await.unlocked).await;
+
+    // when the ramp was last turned off, for hybrid memory
+    let mut last_off: Option<Instant> = None;
 
     loop {
         let unlocked = crate::state::is_unlocked().await;
 
         if unlocked {
-            let evt = timeout(Duration::from_secs(60 * 3), BUTTON_EVENTS.wait()).await;
+            let evt = match crate::settings::get().await.autolock_mins {
+                0 => Ok(next_event().await),
+                mins => {
+                    let delay = Duration::from_secs(60 * mins as u64);
+                    crate::state::set_autolock_deadline(Some(Instant::now() + delay)).await;
+
+                    timeout(delay, next_event()).await
+                }
+            };
+            crate::state::set_autolock_deadline(None).await;
+
             let Ok(evt) = evt else {
+                info!("Auto-locking");
                 blink(1).await;
                 crate::state::set_unlocked(false).await;
                 continue;
             };
             match evt {
-                ButtonEvent::Click1 | ButtonEvent::Hold1 => {
-                    saved_level = with_torch_on(on_ramping(if evt == ButtonEvent::Click1 {
-                        saved_level
+                ButtonEvent::Click(1) | ButtonEvent::Hold(1) => {
+                    let settings = crate::settings::get().await;
+                    let level = with_torch_on(on_ramping(if evt == ButtonEvent::Click(1) {
+                        remembered_level(&settings, last_off)
                     } else {
-                        DEFAULT_LEVEL
+                        settings.default_level
                     }))
                     .await;
+                    if let Some(level) = level {
+                        crate::settings::update(|s| s.saved_level = level).await;
+                    }
+                    last_off = Some(Instant::now());
                 }
                 #[cfg(feature = "mode_fade")]
-                ButtonEvent::Hold2 => {
+                ButtonEvent::Hold(2) => {
                     with_torch_on(on_fadeout()).await;
                 }
                 #[cfg(feature = "mode_strobe")]
-                ButtonEvent::Hold3 => {
+                ButtonEvent::Hold(3) => {
                     with_torch_on(on_strobe()).await;
                 }
                 #[cfg(feature = "mode_croak")]
-                ButtonEvent::Hold4 => {
+                ButtonEvent::Hold(4) => {
                     with_torch_on(on_croak()).await;
                 }
-                ButtonEvent::Click4 => {
+                #[cfg(feature = "mode_beacon")]
+                ButtonEvent::Hold(5) => {
+                    with_torch_on(beacon::on_beacons()).await;
+                }
+                ButtonEvent::Click(3) => {
+                    with_torch_on(battery_check()).await;
+                }
+                ButtonEvent::Click(4) => {
                     blink(1).await;
                     crate::state::set_unlocked(false).await;
                 }
-                _ => {}
-            }
-        } else {
-            let evt = select::select(BUTTON_EVENTS.wait(), LOCKOUT_BUTTON_STATES.wait()).await;
-            match evt {
-                select::Either::Second(ButtonState::Press) => {
-                    crate::power::set_level(30).await;
+                ButtonEvent::Click(5) => {
+                    with_torch_on(temp_check()).await;
                 }
-                select::Either::Second(ButtonState::Depress) => {
-                    crate::power::set_level_gradual(0).await;
+                ButtonEvent::Hold(6) => {
+                    calibrate_temp().await;
                 }
-                select::Either::First(ButtonEvent::Click3) => {
+                ButtonEvent::Click(6) => {
+                    let level = remembered_level(&crate::settings::get().await, last_off);
                     blink(1).await;
-                    crate::state::set_unlocked(true).await;
-                    saved_level = DEFAULT_LEVEL;
+                    with_torch_on(on_momentary(level)).await;
+                }
+                ButtonEvent::Hold(7) => {
+                    config_menu::run(config_menu::GLOBAL_MENU).await;
+                }
+                ButtonEvent::Hold(8) => {
+                    config_menu::run(config_menu::BUTTON_LED_MENU).await;
                 }
                 _ => {}
             }
+        } else {
+            on_lockout().await;
         }
     }
 }
 
+// the light only comes on while the button is held, three clicks unlock
+async fn on_lockout() {
+    // subscribed for as long as the light is locked, so that a release can't
+    // slip by while the level is being set
+    let mut states = subscribe_states();
+
+    loop {
+        match select::select(next_event(), states.next()).await {
+            select::Either::Second(Timestamped {
+                value: ButtonState::Press,
+                ..
+            }) => {
+                let settings = crate::settings::get().await;
+                let ramp = crate::ramp::Ramp::from_settings(&settings);
+
+                if settings.lockout_level != 0 {
+                    crate::power::set_level(ramp.clamp(settings.lockout_level)).await;
+                }
+            }
+            select::Either::Second(Timestamped {
+                value: ButtonState::Depress,
+                ..
+            }) => {
+                crate::power::set_level_gradual(0).await;
+            }
+            select::Either::First(ButtonEvent::Click(3)) => {
+                blink(1).await;
+                crate::state::set_unlocked(true).await;
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn remembered_level(settings: &crate::settings::Settings, last_off: Option<Instant>) -> u8 {
+    match settings.memory_mode {
+        MemoryMode::Automatic => settings.saved_level,
+        MemoryMode::Manual => settings.manual_level,
+        MemoryMode::Hybrid => {
+            let timeout = Duration::from_secs(60 * settings.hybrid_timeout_mins as u64);
+
+            match last_off {
+                Some(t) if t.elapsed() < timeout => settings.saved_level,
+                _ => settings.manual_level,
+            }
+        }
+    }
+}
+
+// the light is only on while the button is held, until six clicks get back out
+async fn on_momentary(level: u8) {
+    // the remembered level may be from before the ramp was narrowed
+    let ramp = crate::ramp::Ramp::from_settings(&crate::settings::get().await);
+    let level = ramp.clamp(level);
+
+    info!("Momentary mode at {}", level);
+
+    let mut states = subscribe_states();
+
+    loop {
+        match select::select(next_event(), states.next()).await {
+            select::Either::Second(Timestamped {
+                value: ButtonState::Press,
+                ..
+            }) => {
+                crate::power::set_level(level).await;
+            }
+            select::Either::Second(Timestamped {
+                value: ButtonState::Depress,
+                ..
+            }) => {
+                crate::power::set_level(0).await;
+            }
+            select::Either::First(ButtonEvent::Click(6)) => {
+                crate::power::set_level(0).await;
+                blink(1).await;
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+async fn battery_check() {
+    let volts = *crate::monitoring::RESTING_VOLTAGE.lock().await;
+    let tenths: u8 = (volts.0 * I16F16!(10)).round().saturating_to_num();
+
+    info!("Battery check: {}", tenths);
+
+    crate::power::blink_digits(&[tenths / 10, tenths % 10]).await;
+}
+
+async fn temp_check() {
+    let temp = *crate::monitoring::TEMP.lock().await;
+    let degrees: i16 = temp.0.round().saturating_to_num();
+
+    info!("Temperature check: {}", degrees);
+
+    // there's no blinking a minus sign, anything below freezing reads as zero
+    crate::power::blink_number(degrees.max(0) as u32).await;
+}
+
+// the user clicks out the real ambient temperature in degrees, the difference
+// to what we're measuring gets stored as an offset for the sensor
+async fn calibrate_temp() {
+    blink(2).await;
+
+    let config_menu::Entry::Value(ambient) = config_menu::read_number().await else {
+        return;
+    };
+
+    let measured = *crate::monitoring::TEMP.lock().await;
+    let error: i16 = (I16F16::from_num(ambient) - measured.0)
+        .round()
+        .saturating_to_num();
+
+    crate::settings::update(|s| {
+        s.temp_offset = (s.temp_offset as i16 + error).clamp(i8::MIN as i16, i8::MAX as i16) as i8;
+    })
+    .await;
+
+    info!(
+        "Calibrated temperature to {}, offset now {}",
+        ambient,
+        crate::settings::get().await.temp_offset
+    );
+
+    blink(1).await;
+}
+
 #[cfg(feature = "mode_strobe")]
 async fn on_strobe() {
     use core::cell::Cell;
 
-    let level = Cell::new(DEFAULT_LEVEL);
+    let settings = crate::settings::get().await;
+    let ramp = crate::ramp::Ramp::from_settings(&settings);
+    let level = Cell::new(ramp.clamp(settings.default_level));
     let period = Cell::new(Duration::from_hz(10));
 
     let strobe = async {
@@ -311,22 +499,22 @@ async fn on_strobe() {
     let control = async {
         let mut last_hold_release = Instant::now();
         loop {
-            match BUTTON_EVENTS.wait().await {
-                ButtonEvent::Click1 => {
+            match next_event().await {
+                ButtonEvent::Click(1) => {
                     return;
                 }
-                ButtonEvent::Hold1 => {
+                ButtonEvent::Hold(1) => {
                     let direction = if last_hold_release.elapsed() > Duration::from_millis(500) {
                         1
                     } else {
                         -1
                     };
                     loop {
-                        if timeout(Some(Duration::from_millis(200)), BUTTON_EVENTS.wait())
+                        if timeout(Some(Duration::from_millis(200)), next_event())
                             .await
                             .is_err()
                         {
-                            level.set(level.get().saturating_add_signed(direction * 4));
+                            level.set(ramp.nudge(level.get(), direction * 4));
                         } else {
                             break;
                         }
@@ -335,18 +523,18 @@ async fn on_strobe() {
                         last_hold_release = Instant::now();
                     }
                 }
-                ButtonEvent::Hold2 => loop {
-                    if timeout(Some(Duration::from_millis(100)), BUTTON_EVENTS.wait())
+                ButtonEvent::Hold(2) => loop {
+                    if timeout(Some(Duration::from_millis(100)), next_event())
                         .await
                         .is_err()
                     {
-                        level.set(level.get().saturating_sub(4));
+                        level.set(ramp.nudge(level.get(), -4));
                     } else {
                         break;
                     }
                 },
-                ButtonEvent::Hold3 => loop {
-                    if timeout(Some(Duration::from_millis(100)), BUTTON_EVENTS.wait())
+                ButtonEvent::Hold(3) => loop {
+                    if timeout(Some(Duration::from_millis(100)), next_event())
                         .await
                         .is_err()
                     {
@@ -357,8 +545,8 @@ async fn on_strobe() {
                         break;
                     }
                 },
-                ButtonEvent::Hold4 => loop {
-                    if timeout(Some(Duration::from_millis(100)), BUTTON_EVENTS.wait())
+                ButtonEvent::Hold(4) => loop {
+                    if timeout(Some(Duration::from_millis(100)), next_event())
                         .await
                         .is_err()
                     {
@@ -387,8 +575,11 @@ async fn on_fadeout() {
         expiry: Instant,
     }
 
+    let settings = crate::settings::get().await;
+    let ramp = crate::ramp::Ramp::from_settings(&settings);
+
     let state = StateHandler::gradual(State {
-        level: DEFAULT_LEVEL,
+        level: ramp.clamp(settings.default_level),
         expiry: Instant::now() + Duration::from_secs(60 * 4),
     });
 
@@ -413,13 +604,13 @@ async fn on_fadeout() {
         Handler::empty()
             .and(StandardAdjustment::new(|d| {
                 state.modify(|State { level, expiry }| State {
-                    level: level.saturating_add_signed(d),
+                    level: ramp.nudge(level, d),
                     expiry,
                 })
             }))
-            .and(Given::new(ButtonEvent::Hold3, || async {
+            .and(Given::new(ButtonEvent::Hold(3), || async {
                 loop {
-                    if timeout(Duration::from_millis(500), BUTTON_EVENTS.wait())
+                    if timeout(Duration::from_millis(500), next_event())
                         .await
                         .is_err()
                     {
@@ -457,46 +648,15 @@ async fn on_fadeout() {
 
 #[cfg(feature = "mode_croak")]
 async fn on_croak() {
-    #[derive(Copy, Clone)]
-    struct State {
-        level: u8,
-        on: bool,
-    }
-
-    let state = StateHandler::instant(State {
-        level: DEFAULT_LEVEL,
-        on: false,
-    });
-
-    let croaker = async {
-        loop {
-            for x in small_morse::encode("croak") {
-                state.modify(|State { level, .. }| State {
-                    level,
-                    on: x.state == small_morse::State::On,
-                });
+    let level = crate::settings::get().await.default_level;
 
-                maitake::time::sleep(core::time::Duration::from_millis(x.duration as u64 * 300))
-                    .await;
-            }
-        }
-    };
-
-    let control = async {
-        Handler::empty()
-            .and(StandardAdjustment::new(|d| {
-                state.modify(|State { level, on }| State {
-                    level: level.saturating_add_signed(d),
-                    on,
-                })
-            }))
-            .run()
-            .await;
-    };
-
-    let level_fut = state.run(|State { level, on }| if on { level } else { 1 });
-
-    embassy_futures::select::select3(croaker, control, level_fut).await;
+    beacon::blink_engine(
+        level,
+        1,
+        || beacon::morse("croak", Duration::from_millis(300)),
+        |_: ButtonEvent| async { ControlFlow::Continue(()) },
+    )
+    .await;
 }
 
 async fn on_ramping(level: u8) -> u8 {
@@ -506,53 +666,146 @@ async fn on_ramping(level: u8) -> u8 {
         level_before_boost: u8,
     }
 
+    let ramp = Cell::new(crate::ramp::Ramp::from_settings(
+        &crate::settings::get().await,
+    ));
+    let level = ramp.get().clamp(level);
+
     let state = StateHandler::gradual(State {
         level,
         level_before_boost: level,
     });
 
+    let rebuild = Cell::new(false);
+
     let control = async {
-        Handler::empty()
-            .and(StandardAdjustment::new(|d| {
+        // the adjustment is rebuilt whenever the ramp changes
+        loop {
+            let r = ramp.get();
+
+            Handler::empty()
+                .and(StandardAdjustment::with_interval(
+                    |d| {
+                        state.modify(
+                            |State {
+                                 level,
+                                 level_before_boost,
+                             }| State {
+                                level: r.adjust(level, d),
+                                level_before_boost,
+                            },
+                        )
+                    },
+                    r.interval(),
+                ))
+                .and(Given::new(ButtonEvent::Click(3), || async {
+                    let style = r.style.toggled();
+                    ramp.set(crate::ramp::Ramp { style, ..r });
+                    rebuild.set(true);
+                    crate::settings::update(|s| s.ramp_style = style).await;
+                    blink(1).await;
+
+                    ControlFlow::Break(Handled::Exit)
+                }))
+                .and(Given::new(ButtonEvent::Click(4), || async {
+                    let level = state.get().level;
+                    let minutes = crate::energy::remaining_minutes(level).await;
+                    info!("Estimated runtime: {} minutes", minutes);
+
+                    crate::power::blink_number_over(level, minutes).await;
+                    maitake::time::sleep(Duration::from_millis(500)).await;
+                    state.tick();
+
+                    ControlFlow::Break(Handled::Handled)
+                }))
+                .and(Given::new(ButtonEvent::Click(5), || async {
+                    let level = state.get().level;
+                    info!("Saving manual memory level {}", level);
+
+                    crate::settings::update(|s| s.manual_level = level).await;
+                    blink(1).await;
+
+                    ControlFlow::Break(Handled::Handled)
+                }))
+                .and(Given::new(ButtonEvent::Hold(7), || async {
+                    config_menu::run(config_menu::RAMP_MENU).await;
+
+                    let r = crate::ramp::Ramp::from_settings(&crate::settings::get().await);
+                    ramp.set(r);
+                    state.modify(|State { level, .. }| State {
+                        level: r.clamp(level),
+                        level_before_boost: r.clamp(level),
+                    });
+                    rebuild.set(true);
+
+                    ControlFlow::Break(Handled::Exit)
+                }))
+                .and(Given::new(ButtonEvent::Click(2), || async {
+                    let ceiling = r.ceiling;
+
+                    if state.get().level != ceiling && !crate::turbo::can_engage().await {
+                        info!("Turbo budget still recovering");
+                        blink(2).await;
+                        state.tick();
+                        return ControlFlow::Break(Handled::Handled);
+                    }
+
+                    state.modify(
+                        |State {
+                             level,
+                             level_before_boost,
+                         }| {
+                            if level == ceiling {
+                                State {
+                                    level: level_before_boost,
+                                    level_before_boost,
+                                }
+                            } else {
+                                State {
+                                    level: ceiling,
+                                    level_before_boost: level,
+                                }
+                            }
+                        },
+                    );
+
+                    ControlFlow::Break(Handled::Handled)
+                }))
+                .run()
+                .await;
+
+            if !rebuild.replace(false) {
+                break;
+            }
+        }
+    };
+
+    // the power loop holds turbo to its budget, follow it down so the ramp
+    // carries on from the level the light is actually at
+    let turbo_budget = async {
+        loop {
+            maitake::time::sleep(Duration::from_millis(250)).await;
+
+            let r = ramp.get();
+            let sustained = r.clamp(crate::settings::get().await.turbo_sustained_level);
+            let level = state.get().level;
+
+            if level >= r.ceiling && level > sustained && !crate::turbo::can_engage().await {
                 state.modify(
                     |State {
-                         level,
-                         level_before_boost,
+                         level_before_boost, ..
                      }| State {
-                        level: level.saturating_add_signed(d),
+                        level: sustained,
                         level_before_boost,
                     },
-                )
-            }))
-            .and(Given::new(ButtonEvent::Click2, || async {
-                state.modify(
-                    |State {
-                         level,
-                         level_before_boost,
-                     }| {
-                        if level == 255 {
-                            State {
-                                level: level_before_boost,
-                                level_before_boost,
-                            }
-                        } else {
-                            State {
-                                level: 255,
-                                level_before_boost: level,
-                            }
-                        }
-                    },
                 );
-
-                ControlFlow::Break(Handled::Handled)
-            }))
-            .run()
-            .await;
+            }
+        }
     };
 
     let level_fut = state.run(|State { level, .. }| level);
 
-    embassy_futures::select::select(control, level_fut).await;
+    embassy_futures::select::select3(control, level_fut, turbo_budget).await;
 
     state.get().level
 }
@@ -0,0 +1,182 @@
+// A shared engine for modes that blink out a pattern, and the beacon family
+// built on it: SOS, a plain periodic beacon and a user entered Morse message.
+
+// croak uses the engine without the rest
+#![cfg_attr(not(feature = "mode_beacon"), allow(unused))]
+
+use core::{cell::Cell, ops::ControlFlow};
+
+use defmt::info;
+use maitake::time::Duration;
+
+use super::{config_menu, Given, Handle, Handled, Handler, StandardAdjustment, StateHandler};
+use crate::{click::ButtonEvent, power::blink};
+
+/// One step of a blink pattern: whether the light is on, and for how long.
+pub type Pulse = (bool, Duration);
+
+/// Play the pattern from `pattern` over and over at a brightness adjustable
+/// with the usual holds, until Click1 or `extra` exits. The level stays within
+/// the ramp floor and ceiling, the off parts of the pattern run at
+/// `off_level`. Returns the level the user ended up at.
+pub async fn blink_engine<I>(
+    level: u8,
+    off_level: u8,
+    mut pattern: impl FnMut() -> I,
+    extra: impl Handle,
+) -> u8
+where
+    I: Iterator<Item = Pulse>,
+{
+    #[derive(Copy, Clone)]
+    struct State {
+        level: u8,
+        on: bool,
+    }
+
+    let ramp = crate::ramp::Ramp::from_settings(&crate::settings::get().await);
+    let state = StateHandler::instant(State {
+        level: ramp.clamp(level),
+        on: false,
+    });
+
+    let player = async {
+        loop {
+            for (on, duration) in pattern() {
+                state.modify(|State { level, .. }| State { level, on });
+
+                maitake::time::sleep(duration).await;
+            }
+        }
+    };
+
+    let control = async {
+        Handler::empty()
+            .and(StandardAdjustment::new(|d| {
+                state.modify(|State { level, on }| State {
+                    level: ramp.nudge(level, d),
+                    on,
+                })
+            }))
+            .and(extra)
+            .run()
+            .await;
+    };
+
+    let level_fut = state.run(|State { level, on }| if on { level } else { off_level });
+
+    embassy_futures::select::select3(player, control, level_fut).await;
+
+    state.get().level
+}
+
+/// Pulses spelling out `message` in Morse, followed by a gap between words.
+pub fn morse(message: &str, unit: Duration) -> impl Iterator<Item = Pulse> + '_ {
+    small_morse::encode(message)
+        .map(move |x| (x.state == small_morse::State::On, unit * x.duration as u32))
+        .chain(core::iter::once((false, unit * 7)))
+}
+
+const LETTERS: &str = "abcdefghijklmnopqrstuvwxyz";
+
+/// Pulses for a single lowercase letter, followed by the gap between letters.
+fn letter(c: u8, unit: Duration) -> impl Iterator<Item = Pulse> {
+    let i = (c - b'a') as usize;
+
+    small_morse::encode(&LETTERS[i..i + 1])
+        .map(move |x| (x.state == small_morse::State::On, unit * x.duration as u32))
+        .chain(core::iter::once((false, unit * 3)))
+}
+
+const MORSE_UNIT: Duration = Duration::from_millis(200);
+
+async fn on_sos(level: u8, extra: impl Handle) -> u8 {
+    blink_engine(level, 1, || morse("sos", MORSE_UNIT), extra).await
+}
+
+async fn on_beacon(level: u8, extra: impl Handle) -> u8 {
+    let read_timing = || async {
+        let s = crate::settings::get().await;
+        let on = Duration::from_millis(s.beacon_on_tenths.max(1) as u64 * 100);
+        let interval = Duration::from_secs(s.beacon_interval_secs as u64);
+
+        (on, interval.saturating_sub(on))
+    };
+
+    let timing = Cell::new(read_timing().await);
+
+    let configure = Given::new(ButtonEvent::Hold(7), || async {
+        config_menu::run(config_menu::BEACON_MENU).await;
+        timing.set(read_timing().await);
+
+        ControlFlow::Break(Handled::Handled)
+    });
+
+    blink_engine(
+        level,
+        0,
+        || {
+            let (on, off) = timing.get();
+            [(true, on), (false, off)].into_iter()
+        },
+        (extra, configure),
+    )
+    .await
+}
+
+async fn on_message(level: u8, extra: impl Handle) -> u8 {
+    let message = Cell::new(crate::settings::get().await.morse_message);
+
+    let configure = Given::new(ButtonEvent::Hold(7), || async {
+        if let Some(m) = config_menu::read_message().await {
+            crate::settings::update(|s| s.morse_message = m).await;
+            message.set(m);
+        }
+
+        ControlFlow::Break(Handled::Handled)
+    });
+
+    blink_engine(
+        level,
+        1,
+        || {
+            message
+                .get()
+                .into_iter()
+                .take_while(u8::is_ascii_lowercase)
+                .flat_map(|c| letter(c, MORSE_UNIT))
+                .chain(core::iter::once((false, MORSE_UNIT * 4)))
+        },
+        (extra, configure),
+    )
+    .await
+}
+
+/// SOS, beacon and message in turn, Click2 moves on to the next.
+pub async fn on_beacons() {
+    let mut level = crate::settings::get().await.default_level;
+    let mut mode = 0u8;
+
+    loop {
+        info!("Beacon mode {}", mode);
+
+        let next = Cell::new(false);
+        let next_handler = Given::new(ButtonEvent::Click(2), || async {
+            next.set(true);
+            ControlFlow::Break(Handled::Exit)
+        });
+
+        level = match mode {
+            0 => on_sos(level, next_handler).await,
+            1 => on_beacon(level, next_handler).await,
+            _ => on_message(level, next_handler).await,
+        };
+
+        if !next.get() {
+            return;
+        }
+
+        mode = (mode + 1) % 3;
+        blink(mode + 1).await;
+    }
+}
@@ -0,0 +1,270 @@
+// Click-to-enter configuration menus. Each item is announced by blinking its
+// number, then the user has a few seconds to click in a value. Leaving an item
+// alone keeps the current value, a hold abandons the rest of the menu.
+
+use core::{cell::Cell, ops::ControlFlow};
+
+use defmt::info;
+use maitake::time::Duration;
+
+use super::{Handled, Handler};
+use crate::{
+    battery_level::Chemistry,
+    button_led::{Pattern as ButtonLedPattern, MAX_BRIGHTNESS},
+    click::ButtonEvent,
+    power::blink,
+    ramp::MemoryMode,
+    settings::Settings,
+    thermal::MAX_CEILING,
+};
+
+const ENTRY_WINDOW: Duration = Duration::from_secs(3);
+
+pub trait MenuItem: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Store the value entered as `clicks` clicks, returns false if it's not
+    /// a valid value for this setting.
+    fn apply(&self, settings: &mut Settings, clicks: u8) -> bool;
+}
+
+/// A menu item that maps the number of clicks onto a single setting.
+pub struct Setting<T> {
+    pub name: &'static str,
+    pub field: fn(&mut Settings) -> &mut T,
+    pub from_clicks: fn(u8) -> Option<T>,
+}
+
+impl<T> MenuItem for Setting<T> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn apply(&self, settings: &mut Settings, clicks: u8) -> bool {
+        match (self.from_clicks)(clicks) {
+            Some(v) => {
+                *(self.field)(settings) = v;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub type Menu = [&'static dyn MenuItem];
+
+pub static RAMP_MENU: &Menu = &[
+    &Setting::<u8> {
+        name: "ramp floor",
+        field: |s| &mut s.ramp_floor,
+        from_clicks: Some,
+    },
+    // counted down from the top
+    &Setting::<u8> {
+        name: "ramp ceiling",
+        field: |s| &mut s.ramp_ceiling,
+        from_clicks: |n| Some(255 - (n - 1)),
+    },
+    &Setting::<u8> {
+        name: "ramp steps",
+        field: |s| &mut s.ramp_steps,
+        from_clicks: |n| (n >= 2).then_some(n),
+    },
+    // in tens of levels
+    &Setting::<u8> {
+        name: "turbo sustained level",
+        field: |s| &mut s.turbo_sustained_level,
+        from_clicks: |n| Some(n.saturating_mul(10)),
+    },
+];
+
+pub static GLOBAL_MENU: &Menu = &[
+    // one click never locks, more lock after one less than that many minutes
+    &Setting::<u8> {
+        name: "autolock minutes",
+        field: |s| &mut s.autolock_mins,
+        from_clicks: |n| Some(n - 1),
+    },
+    // one click follows the battery voltage, more pick a fixed hue
+    &Setting::<u8> {
+        name: "aux colour",
+        field: |s| &mut s.aux_colour,
+        from_clicks: |n| (n <= 9).then_some(n - 1),
+    },
+    // automatic, manual, hybrid
+    &Setting::<MemoryMode> {
+        name: "memory mode",
+        field: |s| &mut s.memory_mode,
+        from_clicks: |n| MemoryMode::from_u8(n - 1),
+    },
+    &Setting::<u8> {
+        name: "hybrid memory minutes",
+        field: |s| &mut s.hybrid_timeout_mins,
+        from_clicks: Some,
+    },
+    // off, moon or low
+    &Setting::<u8> {
+        name: "lockout momentary level",
+        field: |s| &mut s.lockout_level,
+        from_clicks: |n| match n {
+            1 => Some(0),
+            2 => Some(1),
+            3 => Some(30),
+            _ => None,
+        },
+    },
+    // li-ion, lifepo4, li-hv
+    &Setting::<Chemistry> {
+        name: "battery chemistry",
+        field: |s| &mut s.battery,
+        from_clicks: |n| Chemistry::from_u8(n - 1),
+    },
+    // in hundreds of mAh
+    &Setting::<u16> {
+        name: "battery capacity",
+        field: |s| &mut s.capacity_mah,
+        from_clicks: |n| Some(n as u16 * 100),
+    },
+    // in degrees, kept clear of the instant stop in power
+    &Setting::<u8> {
+        name: "max temp",
+        field: |s| &mut s.max_temp,
+        from_clicks: |n| (30..=MAX_CEILING).contains(&n).then_some(n),
+    },
+    // in steps of 4ms
+    &Setting::<u8> {
+        name: "debounce",
+        field: |s| &mut s.debounce_ms,
+        from_clicks: |n| (n <= 25).then(|| n * 4),
+    },
+    // in steps of 50ms, from 100ms
+    &Setting::<u16> {
+        name: "click window",
+        field: |s| &mut s.click_window_ms,
+        from_clicks: |n| (n >= 2).then_some(n as u16 * 50),
+    },
+    &Setting::<u16> {
+        name: "hold time",
+        field: |s| &mut s.hold_ms,
+        from_clicks: |n| (n >= 2).then_some(n as u16 * 50),
+    },
+];
+
+pub static BUTTON_LED_MENU: &Menu = &[
+    // one click is off
+    &Setting::<u8> {
+        name: "button led brightness",
+        field: |s| &mut s.button_led_brightness,
+        from_clicks: |n| (n <= MAX_BRIGHTNESS + 1).then_some(n - 1),
+    },
+    // off, steady, breathing, locator
+    &Setting::<ButtonLedPattern> {
+        name: "button led when locked",
+        field: |s| &mut s.button_led_locked,
+        from_clicks: |n| (n <= 4).then(|| ButtonLedPattern::from_u8(n - 1)).flatten(),
+    },
+    &Setting::<ButtonLedPattern> {
+        name: "button led when unlocked",
+        field: |s| &mut s.button_led_unlocked,
+        from_clicks: |n| (n <= 4).then(|| ButtonLedPattern::from_u8(n - 1)).flatten(),
+    },
+];
+
+pub static BEACON_MENU: &Menu = &[
+    // in tenths of a second
+    &Setting::<u8> {
+        name: "beacon flash length",
+        field: |s| &mut s.beacon_on_tenths,
+        from_clicks: Some,
+    },
+    &Setting::<u8> {
+        name: "beacon interval seconds",
+        field: |s| &mut s.beacon_interval_secs,
+        from_clicks: Some,
+    },
+];
+
+pub enum Entry {
+    Value(u8),
+    Skipped,
+    Cancelled,
+}
+
+/// Add up clicks until the button is left alone for a few seconds.
+pub async fn read_number() -> Entry {
+    let count = &Cell::new(0u8);
+    let cancelled = &Cell::new(false);
+
+    Handler::empty()
+        .and(|e: ButtonEvent| async move {
+            if let ButtonEvent::HoldEnd { .. } = e {
+                return ControlFlow::Continue(());
+            }
+
+            match e.click_count() {
+                Some(n) => {
+                    count.set(count.get().saturating_add(n));
+                    ControlFlow::Break(Handled::Handled)
+                }
+                None => {
+                    cancelled.set(true);
+                    ControlFlow::Break(Handled::Exit)
+                }
+            }
+        })
+        .run_until_idle(ENTRY_WINDOW)
+        .await;
+
+    if cancelled.get() {
+        Entry::Cancelled
+    } else if count.get() == 0 {
+        Entry::Skipped
+    } else {
+        Entry::Value(count.get())
+    }
+}
+
+/// Walk through each item of `menu` in turn.
+pub async fn run(menu: &Menu) {
+    for (i, item) in menu.iter().enumerate() {
+        blink(i as u8 + 1).await;
+
+        let clicks = match read_number().await {
+            Entry::Value(n) => n,
+            Entry::Skipped => continue,
+            Entry::Cancelled => return,
+        };
+
+        let mut accepted = false;
+        crate::settings::update(|s| accepted = item.apply(s, clicks)).await;
+
+        info!(
+            "Config {}: {} clicks, accepted: {}",
+            item.name(),
+            clicks,
+            accepted
+        );
+    }
+}
+
+/// Enter a message one letter at a time, one click for 'a' up to 26 for 'z'.
+/// Leaving a letter alone ends the message, a hold abandons it.
+pub async fn read_message() -> Option<[u8; 8]> {
+    let mut message = [0u8; 8];
+
+    for (i, c) in message.iter_mut().enumerate() {
+        blink(1).await;
+
+        match read_number().await {
+            Entry::Value(n @ 1..=26) => *c = b'a' + n - 1,
+            Entry::Value(_) => {
+                info!("Letter {} out of range", i);
+                return None;
+            }
+            Entry::Skipped if i > 0 => break,
+            Entry::Skipped | Entry::Cancelled => return None,
+        }
+    }
+
+    Some(message)
+}